@@ -0,0 +1,16 @@
+// simplified C
+program : (header+<0.4>) function^;
+header:'#include<'identifier'.h>\n';
+function:functionheader'{''\n'functioncontent'}''\n';
+functionheader:datatype ' ' identifier '(' ')' ;
+datatype: 'int' | 'float' | 'double' | 'char';
+functioncontent: (statement '\n')+ ;
+statement: declaration | ifblock | whileblock;
+declaration: datatype ' ' identifier ' = ' expression ';';
+ifblock: 'if(' condition '){' '\n' functioncontent '}';
+whileblock: 'while(' condition '){' '\n' functioncontent '}';
+condition: expression ' < ' expression ( ' && ' condition )?;
+expression: term | term ' + ' expression | '(' expression ')';
+term: identifier | number;
+number: [0-9];
+identifier: [a-z];
@@ -0,0 +1,104 @@
+// Walk throughput on the simplified C grammar. Run with `cargo bench --bench walk`.
+// Plain std timing so benchmarking needs no extra dependencies.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use resrap_rs::{Resrap, Termination};
+
+const GRAMMAR: &str = include_str!("grammars/C.g4");
+const RUNS: usize = 15;
+
+struct Report {
+    name: &'static str,
+    median: Duration,
+    min: Duration,
+    tokens: usize,
+    bytes: usize,
+}
+
+// Runs `f` once to warm up, then RUNS times. `f` returns (tokens, bytes) produced.
+fn bench(name: &'static str, mut f: impl FnMut() -> (usize, usize)) -> Report {
+    black_box(f());
+    let mut times = Vec::with_capacity(RUNS);
+    let mut produced = (0, 0);
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        produced = black_box(f());
+        times.push(start.elapsed());
+    }
+    times.sort();
+    Report {
+        name,
+        median: times[RUNS / 2],
+        min: times[0],
+        tokens: produced.0,
+        bytes: produced.1,
+    }
+}
+
+fn measure(tokens: &[String]) -> (usize, usize) {
+    (tokens.len(), tokens.iter().map(String::len).sum())
+}
+
+fn main() {
+    let mut rs = Resrap::new();
+    rs.parse_grammar("C".into(), GRAMMAR.into()).unwrap();
+
+    let reports = [
+        bench("parse grammar", || {
+            let mut rs = Resrap::new();
+            rs.parse_grammar("C".into(), GRAMMAR.into()).unwrap();
+            (0, GRAMMAR.len())
+        }),
+        bench("truncate, 1M tokens", || {
+            measure(
+                &rs.generate_with_seed("C", "program".into(), 7, 1_000_000)
+                    .unwrap(),
+            )
+        }),
+        bench("bounded, 1M tokens", || {
+            let termination = Termination::Bounded { max_depth: 64 };
+            measure(
+                &rs.generate_with_termination("C", "program".into(), 7, 1_000_000, termination)
+                    .unwrap(),
+            )
+        }),
+        bench("generator, 1M tokens", || {
+            let generator = rs.generator("C", "program".into(), 7).unwrap();
+            generator
+                .take(1_000_000)
+                .fold((0, 0), |(n, b), token| (n + 1, b + token.len()))
+        }),
+        bench("1000 seeds x 1k tokens", || {
+            (0..1000).fold((0, 0), |(n, b), seed| {
+                let (tokens, bytes) = measure(
+                    &rs.generate_with_seed("C", "program".into(), seed, 1000)
+                        .unwrap(),
+                );
+                (n + tokens, b + bytes)
+            })
+        }),
+    ];
+
+    println!("| benchmark | median | min | ns/token | MB/s |");
+    println!("|---|---|---|---|---|");
+    for r in &reports {
+        let secs = r.median.as_secs_f64();
+        let per_token = if r.tokens > 0 {
+            format!("{:.1}", secs * 1e9 / r.tokens as f64)
+        } else {
+            "-".to_string()
+        };
+        println!(
+            "| {} | {:.2?} | {:.2?} | {} | {:.1} |",
+            r.name,
+            r.median,
+            r.min,
+            per_token,
+            r.bytes as f64 / secs / 1e6
+        );
+    }
+}
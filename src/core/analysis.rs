@@ -0,0 +1,140 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::core::{
+    diagnostic::{Diagnostic, Span},
+    frozen_graph::FrozenSyntaxGraph,
+    graph::NodeType,
+    lint,
+    parser::Parser,
+};
+
+/// What the semantic pass learned about a single rule.
+#[derive(Debug, Clone)]
+pub struct RuleInfo {
+    pub name: String,
+    pub span: Span,
+    /// Rules referenced from this rule's body.
+    pub calls: Vec<u32>,
+    /// Tokens in the shortest finished derivation, None if the rule can never finish.
+    /// Taken from the completion lengths computed when the graph was frozen.
+    pub min_len: Option<u32>,
+}
+
+/// Rule level facts about a parsed grammar, computed once after parsing.
+#[derive(Debug, Clone, Default)]
+pub struct Analysis {
+    pub rules: HashMap<u32, RuleInfo>,
+    /// Findings of the probability linter, see `lint::lint`.
+    pub lints: Vec<Diagnostic>,
+}
+
+/// Reports every reference to a rule that was never defined.
+pub fn undefined_rules(parser: &Parser) -> Vec<Diagnostic> {
+    parser
+        .ref_spans
+        .iter()
+        .filter(|(id, _)| !parser.def_check.get(id).copied().unwrap_or(false))
+        .map(|(id, span)| {
+            let name = &parser.rev_name_map[id];
+            Diagnostic::error(format!("Undefined rule '{}'", name), span.clone())
+        })
+        .collect()
+}
+
+impl Analysis {
+    pub fn new(parser: &Parser, graph: &FrozenSyntaxGraph) -> Self {
+        let mut rules = HashMap::new();
+        for (&id, span) in &parser.def_spans {
+            rules.insert(
+                id,
+                RuleInfo {
+                    name: parser.rev_name_map[&id].clone(),
+                    span: span.clone(),
+                    calls: calls(graph, id),
+                    min_len: graph
+                        .node(id)
+                        .ok()
+                        .map(|header| header.min_tokens)
+                        .filter(|&len| len != u32::MAX),
+                },
+            );
+        }
+
+        Analysis {
+            rules,
+            lints: lint::lint(parser),
+        }
+    }
+
+    /// Warnings that hold no matter where generation starts.
+    pub fn warnings(&self) -> Vec<Diagnostic> {
+        let mut diags: Vec<Diagnostic> = self
+            .rules
+            .values()
+            .filter(|info| info.min_len.is_none())
+            .map(|info| {
+                Diagnostic::warning(
+                    format!("Rule '{}' can never finish", info.name),
+                    info.span.clone(),
+                )
+            })
+            .collect();
+        diags.sort_by_key(|d| d.span.start);
+        diags
+    }
+
+    /// Warnings for rules that generation from `start` can never visit.
+    pub fn unreachable(&self, start: u32) -> Vec<Diagnostic> {
+        let mut seen = HashSet::from([start]);
+        let mut stack = vec![start];
+        while let Some(id) = stack.pop() {
+            if let Some(info) = self.rules.get(&id) {
+                for &callee in &info.calls {
+                    if seen.insert(callee) {
+                        stack.push(callee);
+                    }
+                }
+            }
+        }
+
+        let mut diags: Vec<Diagnostic> = self
+            .rules
+            .iter()
+            .filter(|(id, _)| !seen.contains(id))
+            .map(|(_, info)| {
+                Diagnostic::warning(
+                    format!("Rule '{}' is unreachable", info.name),
+                    info.span.clone(),
+                )
+            })
+            .collect();
+        diags.sort_by_key(|d| d.span.start);
+        diags
+    }
+}
+
+// Rules referenced anywhere in the body of `rule`
+fn calls(graph: &FrozenSyntaxGraph, rule: u32) -> Vec<u32> {
+    let Ok(start) = graph.index_of(rule) else {
+        return vec![];
+    };
+    let mut seen = HashSet::from([start]);
+    let mut stack = vec![start];
+    let mut calls = vec![];
+
+    while let Some(index) = stack.pop() {
+        let node = graph.at(index);
+        if node.typ == NodeType::END {
+            continue;
+        }
+        if node.typ == NodeType::POINTER && !calls.contains(&node.pointer) {
+            calls.push(node.pointer);
+        }
+        for &edge in graph.edges(node) {
+            if seen.insert(edge) {
+                stack.push(edge);
+            }
+        }
+    }
+    calls
+}
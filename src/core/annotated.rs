@@ -0,0 +1,62 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::core::{
+    error::ResrapError,
+    frozen_graph::{FrozenSyntaxGraph, Termination},
+    prng::PRNG,
+    walker::{Origin, Step, Walker},
+};
+
+/// A generated token with the grammar rule that printed it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnotatedToken {
+    pub text: String,
+    /// Innermost rule that was open when the token was printed.
+    pub rule: Arc<str>,
+    /// Byte offset of `text` in the concatenated output.
+    pub offset: usize,
+    pub origin: Origin,
+}
+
+impl FrozenSyntaxGraph {
+    /// Walks like `walk_graph_with`, tagging every token with its rule and position.
+    pub fn walk_graph_annotated(
+        &self,
+        prng: PRNG,
+        start: String,
+        tokens: usize,
+        termination: Termination,
+    ) -> Result<Vec<AnnotatedToken>, ResrapError> {
+        let names: HashMap<u32, Arc<str>> = self
+            .rule_names()
+            .into_iter()
+            .map(|(id, name)| (id, Arc::from(name)))
+            .collect();
+        let name = |id: u32| names.get(&id).cloned().unwrap_or_else(|| Arc::from(""));
+
+        let mut rules = vec![name(self.name_map.get(&start).copied().unwrap_or_default())];
+        let mut walker = Walker::new(self, prng, start, tokens, termination)?;
+        let mut result = vec![];
+        let mut offset = 0;
+
+        loop {
+            match walker.step()? {
+                Step::Enter(id) => rules.push(name(id)),
+                Step::Exit => {
+                    rules.pop();
+                }
+                Step::Emit(text, origin) => {
+                    let len = text.len();
+                    result.push(AnnotatedToken {
+                        text,
+                        rule: rules.last().cloned().unwrap_or_else(|| Arc::from("")),
+                        offset,
+                        origin,
+                    });
+                    offset += len;
+                }
+                Step::Done => return Ok(result),
+            }
+        }
+    }
+}
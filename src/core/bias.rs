@@ -0,0 +1,185 @@
+use std::{collections::HashMap, path::Path};
+
+use crate::core::error::ResrapError;
+
+/// How often each char of a `[...]` class comes up. Only the ratios between the chars of one
+/// class matter, a class where every char weighs 0 picks them all equally.
+pub trait CharBias: Send + Sync {
+    /// Weight of `c`, 0 for never.
+    fn weight(&self, c: char) -> f32;
+}
+
+/// The built-in weightings, by the names `<bias=...>` knows them as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BiasProfile {
+    /// `uniform`, every char equally likely.
+    Uniform,
+    /// `english`, letters as often as in English text. What `[...]` has always used.
+    #[default]
+    English,
+    /// `hex`, hex digits well ahead of everything else.
+    HexDigit,
+    /// `identifier`, lowercase letters and `_` ahead of uppercase and digits, like names in code.
+    CodeIdentifier,
+}
+
+impl BiasProfile {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "uniform" => Some(BiasProfile::Uniform),
+            "english" => Some(BiasProfile::English),
+            "hex" => Some(BiasProfile::HexDigit),
+            "identifier" => Some(BiasProfile::CodeIdentifier),
+            _ => None,
+        }
+    }
+}
+
+impl CharBias for BiasProfile {
+    fn weight(&self, c: char) -> f32 {
+        match self {
+            BiasProfile::Uniform => 1.0,
+            BiasProfile::English => english(c) as f32,
+            BiasProfile::HexDigit if c.is_ascii_hexdigit() => 8.0,
+            BiasProfile::HexDigit => 1.0,
+            BiasProfile::CodeIdentifier => match c {
+                'a'..='z' => english(c) as f32,
+                'A'..='Z' => english(c.to_ascii_lowercase()) as f32 / 3.0,
+                '_' => 8.0,
+                '0'..='9' => 1.0,
+                _ => 0.5,
+            },
+        }
+    }
+}
+
+fn english(r: char) -> i32 {
+    let r_lower = r.to_ascii_lowercase();
+
+    match r_lower {
+        'e' => 12,
+        'a' | 'i' | 'o' => 9,
+        'n' | 'r' | 't' | 's' | 'l' => 6,
+        'c' | 'd' | 'm' | 'u' | 'p' | 'b' | 'g' => 4,
+        'f' | 'h' | 'v' | 'k' | 'w' | 'y' => 3,
+        'j' | 'x' | 'q' | 'z' => 1,
+        _ => {
+            if r.is_uppercase() {
+                english(r.to_ascii_lowercase()) / 2
+            } else if r.is_ascii_digit() {
+                3
+            } else if r == '_' {
+                5
+            } else {
+                1
+            }
+        }
+    }
+}
+
+/// Weights read from a table, like the letter frequencies of another language.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BiasTable {
+    weights: HashMap<char, f32>,
+    /// Weight of chars the table leaves out.
+    default: f32,
+}
+
+impl BiasTable {
+    /// Reads a table with a char and its weight on each line, like `e 16.4`. A `*` line sets
+    /// the weight of chars left out, 1 otherwise, and an uppercase letter left out weighs half
+    /// of its lowercase one. Blank lines and lines starting with `#` are skipped.
+    /// A `\` takes the char after it as it is, so `\# 0.5`, `\* 2`, `\\ 1` and `\  3` weigh
+    /// `#`, `*`, `\` and a space.
+    pub fn parse(text: &str) -> Result<Self, ResrapError> {
+        let mut table = BiasTable {
+            weights: HashMap::new(),
+            default: 1.0,
+        };
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim_start();
+            if line.trim_end().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |msg: String| ResrapError::InvalidBiasTable { line: i + 1, msg };
+            let entry = split_entry(line)
+                .and_then(|(ch, weight)| Some((ch, weight.trim().parse::<f32>().ok()?)));
+            match entry {
+                Some((_, weight)) if weight < 0.0 || !weight.is_finite() => {
+                    return Err(invalid(format!("{} is not a weight", weight)));
+                }
+                Some((None, weight)) => table.default = weight,
+                Some((Some(ch), weight)) => {
+                    table.weights.insert(ch, weight);
+                }
+                None => {
+                    return Err(invalid(format!(
+                        "expected a char and its weight, got '{}'",
+                        line.trim_end()
+                    )));
+                }
+            }
+        }
+        Ok(table)
+    }
+
+    /// Reads a table from a file, see `parse`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ResrapError> {
+        let text = std::fs::read_to_string(path)?;
+        Self::parse(&text)
+    }
+}
+
+// Splits a line into its char, None for an unescaped `*`, and the rest holding the weight
+fn split_entry(line: &str) -> Option<(Option<char>, &str)> {
+    let mut chars = line.chars();
+    let ch = match chars.next()? {
+        '\\' => Some(chars.next()?),
+        '*' => None,
+        ch => Some(ch),
+    };
+    let rest = chars.as_str();
+    rest.starts_with(char::is_whitespace).then_some((ch, rest))
+}
+
+impl CharBias for BiasTable {
+    fn weight(&self, c: char) -> f32 {
+        if let Some(&weight) = self.weights.get(&c) {
+            return weight;
+        }
+        let lower: Vec<char> = c.to_lowercase().collect();
+        match lower[..] {
+            [lower] if lower != c => self
+                .weights
+                .get(&lower)
+                .map_or(self.default, |weight| weight / 2.0),
+            _ => self.default,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaped_chars_get_weights() {
+        let table =
+            BiasTable::parse("# comment\n\\# 0.5\n\\* 2\n\\\\ 3\n\\  4\n* 7\ne 16\n").unwrap();
+        for (c, weight) in [('#', 0.5), ('*', 2.0), ('\\', 3.0), (' ', 4.0), ('e', 16.0)] {
+            assert_eq!(table.weight(c), weight, "{:?}", c);
+        }
+        assert_eq!(table.weight('E'), 8.0);
+        assert_eq!(table.weight('x'), 7.0);
+    }
+
+    #[test]
+    fn errors_name_their_line() {
+        for (text, bad_line) in [("e 1\n\nxy 2\n", 3), ("# c\n* -1\n", 2), ("\\\n", 1)] {
+            match BiasTable::parse(text) {
+                Err(ResrapError::InvalidBiasTable { line, .. }) => assert_eq!(line, bad_line),
+                other => panic!("{:?} gave {:?}", text, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::Mutex,
+};
+
+use crate::core::{error::ResrapError, file::Lang};
+
+/// Compiles the grammar at `path` from a build script, for `include_grammar!` to embed.
+/// The result goes to `OUT_DIR`, named after the grammar file with an `.rsg` extension, and the
+/// build reruns whenever the grammar changes. Two grammars with the same file name in different
+/// directories would overwrite each other, so the second one fails the build.
+///
+/// A grammar with errors fails the build with its diagnostics, pointing at the offending
+/// lines of the grammar. Warnings and lints show up as cargo warnings.
+pub fn compile_grammar<P: AsRef<Path>>(path: P) -> PathBuf {
+    match try_compile_grammar(path) {
+        Ok(out) => out,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+}
+
+/// Same as `compile_grammar`, but hands the error back instead of failing the build.
+pub fn try_compile_grammar<P: AsRef<Path>>(path: P) -> Result<PathBuf, ResrapError> {
+    let path = path.as_ref();
+    println!("cargo:rerun-if-changed={}", path.display());
+
+    let out_dir = env::var_os("OUT_DIR").ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "OUT_DIR is not set, grammars can only be compiled from a build script",
+        )
+    })?;
+    let stem = path
+        .file_stem()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "grammar path has no name"))?;
+
+    let mut lang = Lang::new();
+    lang.parse_file(path)?;
+    for diag in lang.warnings().into_iter().chain(lang.lint()) {
+        println!("cargo:warning={} ({})", diag.message, diag.span);
+    }
+
+    let mut out = PathBuf::from(out_dir);
+    out.push(stem);
+    out.set_extension("rsg");
+    claim_output(&out, path)?;
+    if let Some(graph) = lang.get_graph() {
+        fs::write(&out, graph.to_bytes())?;
+    }
+    Ok(out)
+}
+
+// Output files written by this build script so far, with the grammar each came from
+static OUTPUTS: Mutex<BTreeMap<PathBuf, PathBuf>> = Mutex::new(BTreeMap::new());
+
+// Compiling the same grammar twice is fine, another grammar under the same name is not
+fn claim_output(out: &Path, source: &Path) -> io::Result<()> {
+    let source = fs::canonicalize(source).unwrap_or_else(|_| source.to_path_buf());
+    let mut outputs = OUTPUTS.lock().unwrap_or_else(|err| err.into_inner());
+    match outputs.get(out) {
+        Some(first) if *first != source => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "grammars {} and {} would both compile to {}, rename one of them",
+                first.display(),
+                source.display(),
+                out.display()
+            ),
+        )),
+        Some(_) => Ok(()),
+        None => {
+            outputs.insert(out.to_path_buf(), source);
+            Ok(())
+        }
+    }
+}
+
+/// Embeds a grammar compiled by `build::compile_grammar` into the binary, as a `&[u8]` for
+/// `Resrap::load_compiled_grammar`. Takes the grammar file's name without its extension.
+///
+/// ```text
+/// // build.rs
+/// resrap_rs::build::compile_grammar("grammars/C.g4");
+///
+/// // main.rs
+/// let mut rs = Resrap::new();
+/// rs.load_compiled_grammar("C".into(), resrap_rs::include_grammar!("C"))?;
+/// ```
+#[macro_export]
+macro_rules! include_grammar {
+    ($name:literal) => {
+        include_bytes!(concat!(env!("OUT_DIR"), "/", $name, ".rsg")).as_slice()
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_name_from_two_directories_is_rejected() {
+        let out = Path::new("/out/dup_test.rsg");
+        claim_output(out, Path::new("/no/such/a/dup_test.g4")).unwrap();
+        claim_output(out, Path::new("/no/such/a/dup_test.g4")).unwrap();
+        let err = claim_output(out, Path::new("/no/such/b/dup_test.g4")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    }
+}
@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+
+use crate::core::{
+    codec::{Fnv64, Reader, Writer},
+    distribution::Distribution,
+    error::ResrapError,
+    frozen_graph::{FrozenSyntaxGraph, FrozenSyntaxNode, NO_INDEX, Repeat},
+    graph::NodeType,
+    regex::Regexer,
+};
+
+const COMPILED_MAGIC: &[u8; 4] = b"RSRG";
+const COMPILED_VERSION: u8 = 5;
+
+// Layout: magic, version, graph, then an FNV-1a checksum of everything before it.
+// Counts and indices are u32, all little endian, see `codec`.
+
+impl FrozenSyntaxGraph {
+    /// Encodes the compiled graph, regex programs included, so it can be loaded back with
+    /// `from_bytes` without scanning or parsing the grammar again.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Writer::new();
+        out.buf.extend_from_slice(COMPILED_MAGIC);
+        out.u8(COMPILED_VERSION);
+
+        out.u32(self.nodes.len() as u32);
+        for node in &self.nodes {
+            out.u32(node.id);
+            out.u8(node.typ.clone() as u8);
+            out.u32(node.pointer);
+            out.u32(node.callee);
+            out.u32(node.print);
+            out.u32(node.print_len);
+            out.u32(node.first_edge);
+            out.u32(node.edge_count);
+            out.u32(node.min_tokens);
+            out.u32(node.min_steps);
+        }
+
+        out.u32(self.edges.len() as u32);
+        for (&edge, &freq) in self.edges.iter().zip(&self.frequencies) {
+            out.u32(edge);
+            out.f32(freq);
+        }
+
+        out.u32(self.texts.len() as u32);
+        for text in &self.texts {
+            out.str(text);
+        }
+
+        out.u32(self.repeats.len() as u32);
+        for repeat in &self.repeats {
+            out.u32(repeat.min);
+            out.u8(repeat.max.is_some() as u8);
+            out.u32(repeat.max.unwrap_or(0));
+            match &repeat.count {
+                Some(count) => {
+                    out.u8(1);
+                    count.encode(&mut out);
+                }
+                None => out.u8(0),
+            }
+        }
+
+        let mut names: Vec<_> = self.name_map.iter().collect();
+        names.sort();
+        out.u32(names.len() as u32);
+        for (name, &id) in names {
+            out.str(name);
+            out.u32(id);
+        }
+
+        self.regexer.encode(&mut out);
+
+        let mut hash = Fnv64::new();
+        hash.write(&out.buf);
+        out.u64(hash.finish());
+        out.buf
+    }
+
+    /// Loads a graph written by `to_bytes`.
+    /// Fails with `InvalidCompiledGrammar` on a different version, a checksum that doesn't
+    /// match, or indices that point outside the graph.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ResrapError> {
+        let invalid = ResrapError::InvalidCompiledGrammar;
+
+        let Some(body_len) = bytes.len().checked_sub(8) else {
+            return Err(invalid("unexpected end of data".to_string()));
+        };
+        let (body, checksum) = bytes.split_at(body_len);
+        if !body.starts_with(COMPILED_MAGIC) {
+            return Err(invalid("not a compiled resrap grammar".to_string()));
+        }
+        let version = body.get(COMPILED_MAGIC.len()).copied().unwrap_or(0);
+        if version != COMPILED_VERSION {
+            return Err(invalid(format!("unsupported version {}", version)));
+        }
+        let mut hash = Fnv64::new();
+        hash.write(body);
+        if hash.finish().to_le_bytes() != checksum {
+            return Err(invalid("checksum mismatch".to_string()));
+        }
+
+        let mut input = Reader::new(&body[COMPILED_MAGIC.len() + 1..]);
+        let mut graph = decode(&mut input).map_err(invalid)?;
+        if !input.is_empty() {
+            return Err(invalid("trailing bytes".to_string()));
+        }
+        validate(&graph).map_err(invalid)?;
+
+        graph.index = graph
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| (node.id, i as u32))
+            .collect();
+        graph.fingerprint = graph.compute_fingerprint();
+        Ok(graph)
+    }
+}
+
+fn decode(input: &mut Reader) -> Result<FrozenSyntaxGraph, String> {
+    let count = input.u32()?;
+    let mut nodes = vec![];
+    for _ in 0..count {
+        let id = input.u32()?;
+        let tag = input.u8()?;
+        let typ = NodeType::from_tag(tag).ok_or(format!("unknown node type {}", tag))?;
+        nodes.push(FrozenSyntaxNode {
+            id,
+            typ,
+            pointer: input.u32()?,
+            callee: input.u32()?,
+            print: input.u32()?,
+            print_len: input.u32()?,
+            first_edge: input.u32()?,
+            edge_count: input.u32()?,
+            min_tokens: input.u32()?,
+            min_steps: input.u32()?,
+        });
+    }
+
+    let count = input.u32()?;
+    let mut edges = vec![];
+    let mut frequencies = vec![];
+    for _ in 0..count {
+        edges.push(input.u32()?);
+        frequencies.push(input.f32()?);
+    }
+
+    let count = input.u32()?;
+    let mut texts = vec![];
+    for _ in 0..count {
+        texts.push(input.str()?);
+    }
+
+    let count = input.u32()?;
+    let mut repeats = vec![];
+    for _ in 0..count {
+        let min = input.u32()?;
+        let bounded = input.u8()?;
+        let max = input.u32()?;
+        let max = match bounded {
+            0 => None,
+            1 => Some(max),
+            flag => return Err(format!("bad repeat bound flag {}", flag)),
+        };
+        let count = match input.u8()? {
+            0 => None,
+            1 => Some(Distribution::decode(input)?),
+            flag => return Err(format!("bad repeat count flag {}", flag)),
+        };
+        repeats.push(Repeat { min, max, count });
+    }
+
+    let count = input.u32()?;
+    let mut name_map = HashMap::new();
+    for _ in 0..count {
+        let name = input.str()?;
+        name_map.insert(name, input.u32()?);
+    }
+
+    let regexer = Regexer::decode(input)?;
+
+    Ok(FrozenSyntaxGraph {
+        nodes,
+        edges,
+        frequencies,
+        texts,
+        repeats,
+        index: HashMap::new(),
+        name_map,
+        regexer,
+        fingerprint: 0,
+    })
+}
+
+// Walks index straight into the arena, so nothing may point outside of it
+fn validate(graph: &FrozenSyntaxGraph) -> Result<(), String> {
+    let nodes = graph.nodes.len();
+    let in_range = |index: u32, len: usize| (index as usize) < len;
+
+    if let Some(&edge) = graph.edges.iter().find(|&&e| !in_range(e, nodes)) {
+        return Err(format!("edge to node {} out of range", edge));
+    }
+    for node in &graph.nodes {
+        let range = node.edge_range();
+        if range.end > graph.edges.len() {
+            return Err(format!("edges of node {} out of range", node.id));
+        }
+        if node.callee != NO_INDEX && !in_range(node.callee, nodes) {
+            return Err(format!("call from node {} out of range", node.id));
+        }
+        let print_ok = match node.typ {
+            NodeType::CH => {
+                node.print == NO_INDEX
+                    || (node.print as usize)
+                        .checked_add(node.print_len as usize)
+                        .is_some_and(|end| end <= graph.texts.len())
+            }
+            NodeType::RX => {
+                node.print == NO_INDEX || in_range(node.print, graph.regexer.patterns.len())
+            }
+            _ => true,
+        };
+        if !print_ok {
+            return Err(format!("text of node {} out of range", node.id));
+        }
+        if node.typ == NodeType::REPEAT
+            && (!in_range(node.print, graph.repeats.len()) || node.edge_count != 2)
+        {
+            return Err(format!("loop node {} is malformed", node.id));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::regex::MAX_PROGRAM;
+    use crate::{Resrap, Termination};
+
+    const GRAMMAR: &str = "
+program: item+ ;
+item: (call | word<0.3>) ';' ;
+call: name '(' (word (',' word)*)? ')' ;
+name: [[a-z_][a-z0-9_]{2,6}]<bias=identifier> ;
+word: [a-z]<len=2..8> | number{1,3} ;
+number: [0-9] ;
+";
+
+    fn compiled() -> (Resrap, Vec<u8>) {
+        let mut resrap = Resrap::new();
+        resrap
+            .parse_grammar("g".to_string(), GRAMMAR.to_string())
+            .unwrap();
+        let bytes = resrap.compile_grammar("g").unwrap();
+        (resrap, bytes)
+    }
+
+    fn rejected(bytes: &[u8]) -> bool {
+        matches!(
+            FrozenSyntaxGraph::from_bytes(bytes),
+            Err(ResrapError::InvalidCompiledGrammar(_))
+        )
+    }
+
+    #[test]
+    fn round_trip_generates_the_same() {
+        let (source, bytes) = compiled();
+        let mut loaded = Resrap::new();
+        loaded
+            .load_compiled_grammar("g".to_string(), &bytes)
+            .unwrap();
+        assert_eq!(loaded.compile_grammar("g").unwrap(), bytes);
+
+        for seed in 0..50 {
+            for termination in [Termination::Truncate, Termination::Bounded { max_depth: 8 }] {
+                let generate = |resrap: &Resrap| {
+                    resrap
+                        .generate_with_termination(
+                            "g",
+                            "program".to_string(),
+                            seed,
+                            40,
+                            termination,
+                        )
+                        .unwrap()
+                };
+                assert_eq!(generate(&source), generate(&loaded));
+            }
+        }
+    }
+
+    #[test]
+    fn longest_regex_round_trips() {
+        let grammar = |picks: usize| format!("s: [{}]<regex>;", "a".repeat(picks));
+        let mut resrap = Resrap::new();
+        // Every `a` is one instruction, the closing `Match` makes it MAX_PROGRAM
+        resrap
+            .parse_grammar("g".to_string(), grammar(MAX_PROGRAM - 1))
+            .unwrap();
+        let bytes = resrap.compile_grammar("g").unwrap();
+        let mut loaded = Resrap::new();
+        loaded
+            .load_compiled_grammar("g".to_string(), &bytes)
+            .unwrap();
+        assert_eq!(
+            loaded
+                .generate_with_seed("g", "s".to_string(), 1, 1)
+                .unwrap(),
+            resrap
+                .generate_with_seed("g", "s".to_string(), 1, 1)
+                .unwrap()
+        );
+
+        for grammar in [
+            grammar(MAX_PROGRAM),
+            format!("s: [{}b*]<regex>;", "a".repeat(4095)),
+        ] {
+            assert!(
+                Resrap::new()
+                    .parse_grammar("g".to_string(), grammar)
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn damaged_bytes_are_rejected() {
+        let (_, bytes) = compiled();
+        for i in [COMPILED_MAGIC.len() + 1, bytes.len() / 2, bytes.len() - 1] {
+            let mut flipped = bytes.clone();
+            flipped[i] ^= 0x10;
+            assert!(rejected(&flipped), "flipped byte {}", i);
+        }
+        for len in [0, 4, bytes.len() / 2, bytes.len() - 1] {
+            assert!(rejected(&bytes[..len]), "truncated to {}", len);
+        }
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let (_, mut bytes) = compiled();
+        let body_len = bytes.len() - 8;
+        bytes[COMPILED_MAGIC.len()] = COMPILED_VERSION + 1;
+        // With a checksum that matches, so only the version can turn it away
+        let mut hash = Fnv64::new();
+        hash.write(&bytes[..body_len]);
+        bytes[body_len..].copy_from_slice(&hash.finish().to_le_bytes());
+        assert!(matches!(
+            FrozenSyntaxGraph::from_bytes(&bytes),
+            Err(ResrapError::InvalidCompiledGrammar(msg)) if msg.contains("version")
+        ));
+    }
+}
@@ -0,0 +1,96 @@
+use crate::core::{
+    error::ResrapError,
+    frozen_graph::{FrozenSyntaxGraph, Termination},
+    prng::PRNG,
+    walker::{Origin, Step, Walker},
+};
+
+/// The derivation tree of a generated snippet.
+/// Flattening its leaves in order gives back the plain token output for the same seed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Derivation {
+    /// A rule and everything it expanded to. Rules cut off by the token limit are left open.
+    Rule {
+        name: String,
+        children: Vec<Derivation>,
+    },
+    /// A single emitted token.
+    Leaf { text: String, origin: Origin },
+}
+
+impl Derivation {
+    /// Iterates over the leaf texts from left to right.
+    pub fn leaves(&self) -> Leaves<'_> {
+        Leaves { stack: vec![self] }
+    }
+}
+
+/// Depth first iterator over the leaves of a `Derivation`.
+pub struct Leaves<'a> {
+    stack: Vec<&'a Derivation>,
+}
+
+impl<'a> Iterator for Leaves<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        while let Some(node) = self.stack.pop() {
+            match node {
+                Derivation::Leaf { text, .. } => return Some(text),
+                Derivation::Rule { children, .. } => self.stack.extend(children.iter().rev()),
+            }
+        }
+        None
+    }
+}
+
+impl FrozenSyntaxGraph {
+    /// Walks like `walk_graph_with`, but keeps track of which rule produced what.
+    pub fn walk_graph_tree(
+        &self,
+        prng: PRNG,
+        start: String,
+        tokens: usize,
+        termination: Termination,
+    ) -> Result<Derivation, ResrapError> {
+        let names = self.rule_names();
+        let rule = |id: u32, children| Derivation::Rule {
+            name: names.get(&id).copied().unwrap_or_default().to_string(),
+            children,
+        };
+
+        // Open rules as (id, children so far), the start rule at the bottom
+        let start_id = self.name_map.get(&start).copied();
+        let mut walker = Walker::new(self, prng, start, tokens, termination)?;
+        let mut open: Vec<(u32, Vec<Derivation>)> = vec![(start_id.unwrap_or_default(), vec![])];
+
+        loop {
+            match walker.step()? {
+                Step::Enter(id) => open.push((id, vec![])),
+                Step::Exit => {
+                    if let Some((id, children)) = open.pop() {
+                        let node = rule(id, children);
+                        if let Some((_, parent)) = open.last_mut() {
+                            parent.push(node);
+                        }
+                    }
+                }
+                Step::Emit(text, origin) => {
+                    if let Some((_, children)) = open.last_mut() {
+                        children.push(Derivation::Leaf { text, origin });
+                    }
+                }
+                Step::Done => break,
+            }
+        }
+
+        // Close whatever the walk left open
+        while open.len() > 1 {
+            let (id, children) = open.pop().unwrap();
+            let node = rule(id, children);
+            open.last_mut().unwrap().1.push(node);
+        }
+        let (id, children) = open.pop().unwrap();
+        Ok(rule(id, children))
+    }
+}
@@ -0,0 +1,117 @@
+use std::{fmt, sync::Arc};
+
+/// A region of grammar source.
+/// Lines and columns are 1-based (columns count chars), `start..end` is a byte range.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Span {
+    pub file: Option<Arc<str>>,
+    pub line: usize,
+    pub column: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let file = self.file.as_deref().unwrap_or("<grammar>");
+        write!(f, "{}:{}:{}", file, self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A message about the grammar tied to the place in the source that caused it.
+/// Once the source is attached, displaying it shows the offending line with a caret.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+    line_text: Option<String>,
+    underline: usize,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, span: Span) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.into(),
+            span,
+            line_text: None,
+            underline: 1,
+        }
+    }
+
+    pub fn warning(message: impl Into<String>, span: Span) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::error(message, span)
+        }
+    }
+
+    /// The full source line the span starts on, if the source was attached.
+    pub fn line_text(&self) -> Option<&str> {
+        self.line_text.as_deref()
+    }
+
+    // Copies the offending line out of the grammar so the diagnostic can render on its own
+    pub(crate) fn with_source(mut self, source: &str) -> Self {
+        let start = self.span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |i| start + i);
+        let end = self.span.end.clamp(start, line_end);
+
+        self.line_text = Some(
+            source[line_start..line_end]
+                .trim_end_matches('\r')
+                .to_string(),
+        );
+        self.underline = source[start..end].chars().count().max(1);
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.severity, self.message)?;
+        if self.span.line == 0 {
+            return Ok(()); // no location known
+        }
+        let gutter = self.span.line.to_string().len();
+        write!(f, "\n{:gutter$}--> {}", "", self.span)?;
+
+        if let Some(line) = &self.line_text {
+            // Keep tabs so the caret lines up with the source in a terminal
+            let pad: String = line
+                .chars()
+                .take(self.span.column.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            write!(f, "\n{:gutter$} |", "")?;
+            write!(f, "\n{} | {}", self.span.line, line)?;
+            write!(
+                f,
+                "\n{:gutter$} | {}{}",
+                "",
+                pad,
+                "^".repeat(self.underline)
+            )?;
+        }
+        Ok(())
+    }
+}
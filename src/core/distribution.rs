@@ -0,0 +1,228 @@
+use crate::core::{
+    codec::{Reader, Writer},
+    prng::PRNG,
+};
+
+/// How many rounds a repetition goes, drawn once when it starts instead of a coin flip per
+/// round. Written in the `<...>` right after `*`, `+` or `{m,n}`. Also how long the strings
+/// of a `[...]` regex are, see `parse_length`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Distribution {
+    /// `uniform{min,max}`, every count from `min` to `max` equally likely.
+    Uniform { min: u32, max: u32 },
+    /// `poisson(mean)`.
+    Poisson { mean: f64 },
+    /// `histogram{count:weight, ...}`, each count as likely as its weight.
+    Histogram {
+        counts: Vec<u32>,
+        cumu_freq: Vec<f32>,
+    },
+}
+
+// Past this Knuth's method gets slow and exp(-mean) underflows
+const MAX_MEAN: f64 = 500.0;
+
+impl Distribution {
+    /// Reads a distribution out of the text of an annotation.
+    /// None if the text doesn't name one, so it can still be read as a probability.
+    pub fn parse(text: &str) -> Option<Result<Self, String>> {
+        let text = text.trim();
+        let name_len = text
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(text.len());
+        if name_len == 0 {
+            return None;
+        }
+        let (name, args) = text.split_at(name_len);
+        Some(match name {
+            "uniform" => parse_uniform(args),
+            "poisson" => parse_poisson(args),
+            "histogram" => parse_histogram(args),
+            _ => Err(format!(
+                "Unknown distribution '{}', expected uniform, poisson or histogram",
+                name
+            )),
+        })
+    }
+
+    /// Reads the value of a `<len=...>`: a range `m..n`, a single length, or any distribution
+    /// `parse` knows.
+    pub fn parse_length(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        if let Some(length) = Self::parse(text) {
+            return length;
+        }
+        let count = |count: &str| {
+            count.trim().parse::<u32>().map_err(|_| {
+                format!(
+                    "Expected a length like 2..12, 5 or poisson(6), got '{}'",
+                    text
+                )
+            })
+        };
+        let (min, max) = match text.split_once("..") {
+            Some((min, max)) => (count(min)?, count(max)?),
+            None => (count(text)?, count(text)?),
+        };
+        if max < min {
+            return Err(format!("Length range {}..{} is empty", min, max));
+        }
+        Ok(Distribution::Uniform { min, max })
+    }
+
+    pub fn sample(&self, prng: &mut PRNG) -> u32 {
+        match self {
+            Distribution::Uniform { min, max } => {
+                let span = (max - min) as f64 + 1.0;
+                // random() can return exactly 1
+                min + ((prng.random() * span) as u32).min(max - min)
+            }
+            Distribution::Poisson { mean } => {
+                let limit = (-mean).exp();
+                let mut count = 0;
+                let mut product = prng.random();
+                while product > limit {
+                    count += 1;
+                    product *= prng.random();
+                }
+                count
+            }
+            Distribution::Histogram { counts, cumu_freq } => {
+                let value = prng.random() as f32;
+                match cumu_freq.iter().position(|&x| x >= value) {
+                    Some(i) => counts[i],
+                    None => counts[counts.len() - 1],
+                }
+            }
+        }
+    }
+
+    pub(crate) fn encode(&self, out: &mut Writer) {
+        match self {
+            Distribution::Uniform { min, max } => {
+                out.u8(0);
+                out.u32(*min);
+                out.u32(*max);
+            }
+            Distribution::Poisson { mean } => {
+                out.u8(1);
+                out.u64(mean.to_bits());
+            }
+            Distribution::Histogram { counts, cumu_freq } => {
+                out.u8(2);
+                out.u32(counts.len() as u32);
+                for (&count, &freq) in counts.iter().zip(cumu_freq) {
+                    out.u32(count);
+                    out.f32(freq);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn decode(input: &mut Reader) -> Result<Self, String> {
+        match input.u8()? {
+            0 => {
+                let (min, max) = (input.u32()?, input.u32()?);
+                if max < min {
+                    return Err(format!("empty uniform distribution {}..{}", min, max));
+                }
+                Ok(Distribution::Uniform { min, max })
+            }
+            1 => {
+                let mean = f64::from_bits(input.u64()?);
+                if !(mean > 0.0 && mean <= MAX_MEAN) {
+                    return Err(format!("poisson mean {} out of range", mean));
+                }
+                Ok(Distribution::Poisson { mean })
+            }
+            2 => {
+                let len = input.u32()?;
+                if len == 0 {
+                    return Err("empty histogram".to_string());
+                }
+                let mut counts = vec![];
+                let mut cumu_freq = vec![];
+                for _ in 0..len {
+                    counts.push(input.u32()?);
+                    cumu_freq.push(input.f32()?);
+                }
+                Ok(Distribution::Histogram { counts, cumu_freq })
+            }
+            tag => Err(format!("unknown distribution {}", tag)),
+        }
+    }
+}
+
+// Strips `open` and `close` off `args`
+fn inside<'a>(args: &'a str, open: char, close: char, usage: &str) -> Result<&'a str, String> {
+    args.trim()
+        .strip_prefix(open)
+        .and_then(|rest| rest.strip_suffix(close))
+        .ok_or(format!("Expected {}", usage))
+}
+
+fn parse_uniform(args: &str) -> Result<Distribution, String> {
+    let usage = "uniform{min,max}";
+    let args = inside(args, '{', '}', usage)?;
+    let (min, max) = args.split_once(',').ok_or(format!("Expected {}", usage))?;
+    let count = |text: &str| {
+        text.trim()
+            .parse::<u32>()
+            .map_err(|_| format!("Expected {}, '{}' is not a count", usage, text.trim()))
+    };
+    let (min, max) = (count(min)?, count(max)?);
+    if max < min {
+        return Err(format!("uniform{{{},{}}} is empty", min, max));
+    }
+    Ok(Distribution::Uniform { min, max })
+}
+
+fn parse_poisson(args: &str) -> Result<Distribution, String> {
+    let usage = "poisson(mean)";
+    let args = inside(args, '(', ')', usage)?;
+    match args.trim().parse::<f64>() {
+        Ok(mean) if mean > 0.0 && mean <= MAX_MEAN => Ok(Distribution::Poisson { mean }),
+        Ok(mean) => Err(format!(
+            "Poisson mean {} is out of range, it has to be above 0 and at most {}",
+            mean, MAX_MEAN
+        )),
+        Err(_) => Err(format!("Expected {}", usage)),
+    }
+}
+
+fn parse_histogram(args: &str) -> Result<Distribution, String> {
+    let usage = "histogram{count:weight, ...}";
+    let args = inside(args, '{', '}', usage)?;
+    let mut counts = vec![];
+    let mut weights = vec![];
+    for entry in args.split(',') {
+        let parsed = entry.split_once(':').and_then(|(count, weight)| {
+            Some((
+                count.trim().parse::<u32>().ok()?,
+                weight.trim().parse::<f32>().ok()?,
+            ))
+        });
+        match parsed {
+            Some((_, weight)) if weight < 0.0 || !weight.is_finite() => {
+                return Err(format!("Histogram weight {} is not a weight", weight));
+            }
+            Some((count, weight)) => {
+                counts.push(count);
+                weights.push(weight);
+            }
+            None => return Err(format!("Expected {}, got '{}'", usage, entry.trim())),
+        }
+    }
+    let sum: f32 = weights.iter().sum();
+    if sum <= 0.0 {
+        return Err("Every count in the histogram has weight 0".to_string());
+    }
+
+    let mut cumu_freq = Vec::with_capacity(weights.len());
+    let mut total = 0.0;
+    for weight in weights {
+        total += weight / sum;
+        cumu_freq.push(total);
+    }
+    Ok(Distribution::Histogram { counts, cumu_freq })
+}
@@ -0,0 +1,83 @@
+use std::{error::Error, fmt, io};
+
+use crate::core::diagnostic::Diagnostic;
+
+/// Everything that can go wrong while loading a grammar or generating from it.
+#[derive(Debug)]
+pub enum ResrapError {
+    /// The grammar file could not be read.
+    Io(io::Error),
+    /// The grammar text could not be split into tokens.
+    /// Also carries the parse errors of the statements before the scan error.
+    Scan(Vec<Diagnostic>),
+    /// The tokens do not form a valid grammar, one diagnostic per problem.
+    Parse(Vec<Diagnostic>),
+    /// No grammar was loaded under this name.
+    UnknownGrammar(String),
+    /// The grammar has no rule with this name to start generation from.
+    UnknownStartRule(String),
+    /// The rule has no finite derivation, so no complete sentence can start from it.
+    UnfinishableRule(String),
+    /// A node points at an ID that does not exist in the graph.
+    DanglingNode(u32),
+    /// Saved session bytes are truncated, corrupt or from an unsupported version.
+    InvalidSnapshot(String),
+    /// A saved session was made with a different grammar than the one it is restored on.
+    GrammarChanged,
+    /// A compiled grammar is truncated, corrupt or from an unsupported version.
+    InvalidCompiledGrammar(String),
+    /// A job was submitted to a worker pool that hasn't been started or was shut down.
+    PoolNotRunning,
+    /// A char weight table could not be read, with the line it failed on, see `BiasTable::parse`.
+    InvalidBiasTable { line: usize, msg: String },
+    /// A worker pool job panicked while generating, with the panic message.
+    GenerationPanicked(String),
+}
+
+impl fmt::Display for ResrapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResrapError::Io(err) => write!(f, "failed to read grammar: {}", err),
+            ResrapError::Scan(diags) | ResrapError::Parse(diags) => {
+                for (i, diag) in diags.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", diag)?;
+                }
+                Ok(())
+            }
+            ResrapError::UnknownGrammar(name) => write!(f, "no grammar named '{}'", name),
+            ResrapError::UnknownStartRule(name) => write!(f, "no rule named '{}'", name),
+            ResrapError::UnfinishableRule(name) => write!(f, "rule '{}' can never finish", name),
+            ResrapError::DanglingNode(id) => write!(f, "node {} not found in graph", id),
+            ResrapError::InvalidSnapshot(msg) => write!(f, "invalid session snapshot: {}", msg),
+            ResrapError::GrammarChanged => {
+                write!(f, "session snapshot was saved with a different grammar")
+            }
+            ResrapError::InvalidCompiledGrammar(msg) => {
+                write!(f, "invalid compiled grammar: {}", msg)
+            }
+            ResrapError::PoolNotRunning => write!(f, "worker pool is not running"),
+            ResrapError::InvalidBiasTable { line, msg } => {
+                write!(f, "invalid bias table, line {}: {}", line, msg)
+            }
+            ResrapError::GenerationPanicked(msg) => write!(f, "generation panicked: {}", msg),
+        }
+    }
+}
+
+impl Error for ResrapError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ResrapError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ResrapError {
+    fn from(err: io::Error) -> Self {
+        ResrapError::Io(err)
+    }
+}
@@ -1,72 +1,126 @@
+use crate::core::analysis::Analysis;
+use crate::core::bias::CharBias;
+use crate::core::diagnostic::Diagnostic;
+use crate::core::distribution::Distribution;
+use crate::core::error::ResrapError;
 use crate::core::frozen_graph::FrozenSyntaxGraph;
 use crate::core::graph_builder::GraphBuilder;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::collections::HashMap;
 use std::path::Path;
+use std::sync::Arc;
+
+/// Settings for a whole grammar, applied when it is parsed.
+#[derive(Clone, Default)]
+pub struct LangConfig {
+    /// Length of the strings every `[...]` without its own `<len=...>` prints.
+    /// None leaves plain classes like `[a-z]` at 3 to 7 chars and the rest to their regex.
+    pub regex_length: Option<Distribution>,
+    /// Weighs the chars of every `[...]` without its own `<bias=...>`, English when None.
+    pub bias: Option<Arc<dyn CharBias>>,
+    /// Weightings `<bias=name>` can pick by name, next to the built-in `BiasProfile`s.
+    /// Like a `BiasTable` of German letter frequencies under "german".
+    pub bias_tables: HashMap<String, Arc<dyn CharBias>>,
+}
 
-/// ParseFile reads a file and returns statements split by lines ending with ';',
-/// skipping lines starting with "//".
-fn parse_file<P: AsRef<Path>>(filename: P) -> Result<Vec<String>, std::io::Error> {
-    let file = File::open(filename)?;
-    let reader = BufReader::new(file);
-
-    let mut statements = Vec::new();
-    let mut current = String::new();
+pub struct Lang {
+    graph: Option<Arc<FrozenSyntaxGraph>>,
+    analysis: Analysis,
+    source: String,
+    config: LangConfig,
+}
 
-    for line in reader.lines() {
-        let line = line?;
-        let line = line.trim();
+impl Lang {
+    pub fn new() -> Self {
+        Self::with_config(LangConfig::default())
+    }
 
-        // Skip comment or empty lines
-        if line.starts_with("//") || line.is_empty() {
-            continue;
+    pub fn with_config(config: LangConfig) -> Self {
+        Lang {
+            graph: None,
+            analysis: Analysis::default(),
+            source: String::new(),
+            config,
         }
+    }
 
-        // Accumulate this line
-        current.push_str(line);
-        current.push(' ');
+    pub fn get_graph(&self) -> Option<&FrozenSyntaxGraph> {
+        self.graph.as_deref()
+    }
 
-        // If line ends with ';', finalize statement
-        if line.ends_with(';') {
-            let stmt = current.trim().to_string();
-            statements.push(stmt);
-            current.clear();
-        }
+    /// The graph as a handle that can be sent to other threads.
+    pub fn shared_graph(&self) -> Option<Arc<FrozenSyntaxGraph>> {
+        self.graph.clone()
     }
 
-    Ok(statements)
-}
+    /// Reads the grammar at `filename` as is, so diagnostics point at its real lines.
+    /// `//` comments are skipped by the scanner.
+    pub fn parse_file<P: AsRef<Path>>(&mut self, filename: P) -> Result<(), ResrapError> {
+        let path = filename.as_ref();
+        let content = std::fs::read_to_string(path)?;
 
-pub struct Lang {
-    graph: Option<FrozenSyntaxGraph>,
-}
+        self.build(content, Some(Arc::from(path.to_string_lossy())))
+    }
 
-impl Lang {
-    pub fn new() -> Self {
-        Lang { graph: None }
+    pub fn parse_string(&mut self, data: String) -> Result<(), ResrapError> {
+        self.build(data, None)
     }
 
-    pub fn get_graph(&self) -> Option<&FrozenSyntaxGraph> {
-        self.graph.as_ref()
+    /// Loads a graph saved with `FrozenSyntaxGraph::to_bytes` instead of parsing grammar text.
+    /// The grammar source isn't part of it, so warnings and lints come back empty.
+    pub fn load_compiled(&mut self, bytes: &[u8]) -> Result<(), ResrapError> {
+        let graph = FrozenSyntaxGraph::from_bytes(bytes)?;
+        self.graph = Some(Arc::new(graph));
+        self.analysis = Analysis::default();
+        self.source = String::new();
+        Ok(())
     }
 
-    pub fn parse_file<P: AsRef<Path>>(&mut self, filename: P) -> Result<(), String> {
-        let lines = parse_file(filename).map_err(|e| format!("Failed to read file: {}", e))?;
+    pub fn load_compiled_file<P: AsRef<Path>>(&mut self, filename: P) -> Result<(), ResrapError> {
+        let bytes = std::fs::read(filename)?;
+        self.load_compiled(&bytes)
+    }
 
-        let mut gb = GraphBuilder::new();
-        let content = lines.join("");
-        gb.start_generation(content)?;
+    fn build(&mut self, data: String, file: Option<Arc<str>>) -> Result<(), ResrapError> {
+        let mut gb = GraphBuilder::new(self.config.clone());
+        gb.start_generation(data.clone(), file)?;
 
-        self.graph = Some(gb.take_graph());
+        let (graph, analysis) = gb.take();
+        self.graph = Some(Arc::new(graph));
+        self.analysis = analysis;
+        self.source = data;
         Ok(())
     }
 
-    pub fn parse_string(&mut self, data: String) -> Result<(), String> {
-        let mut gb = GraphBuilder::new();
-        gb.start_generation(data)?;
+    /// Problems found while loading that don't stop generation, like rules that can never finish.
+    pub fn warnings(&self) -> Vec<Diagnostic> {
+        self.attach(self.analysis.warnings())
+    }
 
-        self.graph = Some(gb.take_graph());
-        Ok(())
+    /// Lint mode: flags probability annotations that are out of range, have no effect,
+    /// leave a branch with nothing to pick, or build `^` loops whose every way out weighs 0.
+    pub fn lint(&self) -> Vec<Diagnostic> {
+        self.attach(self.analysis.lints.clone())
+    }
+
+    /// Load time warnings plus every rule that generation from `start` can never reach.
+    pub fn check(&self, start: &str) -> Result<Vec<Diagnostic>, ResrapError> {
+        let id = self
+            .graph
+            .as_ref()
+            .and_then(|g| g.name_map.get(start).filter(|id| g.index.contains_key(id)))
+            .ok_or_else(|| ResrapError::UnknownStartRule(start.to_string()))?;
+
+        let mut diags = self.analysis.warnings();
+        diags.extend(self.analysis.unreachable(*id));
+        diags.sort_by_key(|d| d.span.start);
+        Ok(self.attach(diags))
+    }
+
+    fn attach(&self, diags: Vec<Diagnostic>) -> Vec<Diagnostic> {
+        diags
+            .into_iter()
+            .map(|d| d.with_source(&self.source))
+            .collect()
     }
 }
 
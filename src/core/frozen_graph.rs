@@ -1,105 +1,258 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, ops::Range};
 
-use crate::core::{graph::NodeType, prng::PRNG, regex::Regexer};
+use crate::core::{
+    codec::{Fnv64, Writer},
+    distribution::Distribution,
+    error::ResrapError,
+    graph::NodeType,
+    prng::PRNG,
+    regex::Regexer,
+    walker::{Step, Walker},
+};
 
+/// Stands in for an index that doesn't lead anywhere, like a call to an undefined rule.
+pub const NO_INDEX: u32 = u32::MAX;
+
+/// The graph a walk runs on, laid out flat so walking needs no hashing or refcounting.
+/// Nodes sit in one Vec sorted by ID and point at each other by index, their edges are
+/// ranges of `edges`, and everything they print is resolved up front.
 pub struct FrozenSyntaxGraph {
-    pub node_ref: HashMap<u32, Arc<FrozenSyntaxNode>>,
+    pub nodes: Vec<FrozenSyntaxNode>,
+    /// Edge targets of every node back to back, as indices into `nodes`.
+    pub edges: Vec<u32>,
+    /// Cumulative frequency of the edge at the same position in `edges`.
+    pub frequencies: Vec<f32>,
+    /// Unescaped text of every literal.
+    pub texts: Vec<String>,
+    /// Bounds of every counted loop.
+    pub repeats: Vec<Repeat>,
+    /// Node ID to its index in `nodes`.
+    pub index: HashMap<u32, u32>,
     pub name_map: HashMap<String, u32>,
-    pub print_map: HashMap<u32, String>,
     pub regexer: Regexer,
+    /// Hash of everything that shapes the output, see `compute_fingerprint`.
+    pub fingerprint: u64,
 }
 
 pub struct FrozenSyntaxNode {
-    pub options: Vec<FrozenSyntaxEdge>,
-    pub cumulative_frequency: Vec<f32>,
     pub id: u32,
     pub typ: NodeType,
+    /// ID of the rule a POINTER calls.
     pub pointer: u32,
+    /// Index of that rule's header, `NO_INDEX` if the rule doesn't exist.
+    pub callee: u32,
+    /// What the node prints, an index into `texts` for CH and into the regex programs for RX.
+    /// For a REPEAT it is the loop's bounds in `repeats` instead.
+    pub print: u32,
+    /// Literals a CH node prints one after another, starting at `print`. See `optimize`.
+    pub print_len: u32,
+    pub first_edge: u32,
+    pub edge_count: u32,
+    /// Fewest tokens still to print before this node's rule can end, u32::MAX if it can't.
+    pub min_tokens: u32,
+    /// Nodes visited along that shortest completion, breaks ties between equally short paths.
+    pub min_steps: u32,
+}
+
+impl FrozenSyntaxNode {
+    pub fn edge_range(&self) -> Range<usize> {
+        let start = self.first_edge as usize;
+        start..start + self.edge_count as usize
+    }
+}
+
+/// How often a counted loop goes round its body, from `{n}`, `{m,n}` or `{m,}`, or from a
+/// `*` or `+` with a length distribution.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Repeat {
+    pub min: u32,
+    /// None when there is no upper bound.
+    pub max: Option<u32>,
+    /// Draws the count once the loop starts, clamped to `min` and `max`.
+    /// Without one every round past `min` is a weighted coin flip.
+    pub count: Option<Distribution>,
+}
+
+/// How a walk decides when it is done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Termination {
+    /// Stop as soon as the token count is reached, even in the middle of a rule.
+    #[default]
+    Truncate,
+    /// Treat the token count as a budget. Once a choice would overrun it, or the rule stack is
+    /// `max_depth` deep, every pending rule takes its shortest completion instead.
+    /// The output is then always a complete sentence, usually close to the budget.
+    Bounded { max_depth: usize },
+    /// Treat the token count as a soft target. The walk carries on past it until every open
+    /// rule has reached its END, and doesn't loop the start rule again after that. Past the
+    /// target every choice takes its shortest completion, so the walk always ends.
+    Complete,
 }
 
-pub struct FrozenSyntaxEdge {
-    pub node: Arc<FrozenSyntaxNode>,
+/// Output of a walk that finishes its rules instead of cutting at the token count.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Completed {
+    pub tokens: Vec<String>,
+    /// How many tokens past the requested count the walk had to go.
+    pub overshoot: usize,
 }
+
 impl FrozenSyntaxGraph {
     pub fn walk_graph(
         &self,
-        mut prng: PRNG,
+        prng: PRNG,
+        start: String,
+        tokens: usize,
+    ) -> Result<Vec<String>, ResrapError> {
+        self.walk_graph_with(prng, start, tokens, Termination::Truncate)
+    }
+
+    pub fn walk_graph_with(
+        &self,
+        prng: PRNG,
         start: String,
         tokens: usize,
-    ) -> Result<Vec<String>, &str> {
+        termination: Termination,
+    ) -> Result<Vec<String>, ResrapError> {
+        let mut walker = Walker::new(self, prng, start, tokens, termination)?;
         let mut result: Vec<String> = vec![];
-        let mut graph_stack: Vec<u32> = vec![];
+        loop {
+            match walker.step()? {
+                Step::Emit(text, _) => result.push(text),
+                Step::Done => return Ok(result),
+                Step::Enter(_) | Step::Exit => {}
+            }
+        }
+    }
 
-        if let Some(start_id) = self.name_map.get(&start) {
-            let mut printed_tokens: usize = 0;
-            let mut current_id = *start_id;
+    /// Walks with `Termination::Complete` and reports how far past `tokens` it went.
+    pub fn walk_graph_complete(
+        &self,
+        prng: PRNG,
+        start: String,
+        tokens: usize,
+    ) -> Result<Completed, ResrapError> {
+        let result = self.walk_graph_with(prng, start, tokens, Termination::Complete)?;
+        Ok(Completed {
+            overshoot: result.len().saturating_sub(tokens),
+            tokens: result,
+        })
+    }
 
-            loop {
-                // Always fetch fresh from node_ref
-                let current = self
-                    .node_ref
-                    .get(&current_id)
-                    .ok_or("Node not found in graph")?;
+    // Rule header IDs back to the rule names
+    pub(crate) fn rule_names(&self) -> HashMap<u32, &str> {
+        self.name_map
+            .iter()
+            .map(|(name, &id)| (id, name.as_str()))
+            .collect()
+    }
 
-                if printed_tokens >= tokens {
-                    return Ok(result);
-                }
+    /// Looks a node up by ID. Walks go by index, see `at`.
+    pub(crate) fn node(&self, id: u32) -> Result<&FrozenSyntaxNode, ResrapError> {
+        Ok(self.at(self.index_of(id)?))
+    }
 
-                match current.typ {
-                    NodeType::CH => {
-                        if let Some(content) = self.print_map.get(&current.id) {
-                            result.push(unescape_string(&content));
-                            printed_tokens += 1;
-                        }
-                    }
-                    NodeType::RX => {
-                        if let Some(content) = self.print_map.get(&current.id) {
-                            let content = self.regexer.generate_string(content, &mut prng);
-                            result.push(content);
-                            printed_tokens += 1;
-                        }
-                    }
-                    NodeType::POINTER => {
-                        if let Some(ret_node) = current.options.first() {
-                            graph_stack.push(ret_node.node.id);
-                            current_id = current.pointer;
-                        }
-                        continue;
-                    }
-                    NodeType::END => {
-                        if !graph_stack.is_empty() {
-                            let ret_node = graph_stack.pop().unwrap();
-                            current_id = ret_node;
+    pub(crate) fn index_of(&self, id: u32) -> Result<u32, ResrapError> {
+        self.index
+            .get(&id)
+            .copied()
+            .ok_or(ResrapError::DanglingNode(id))
+    }
 
-                            continue;
-                        }
+    // Indices come from the graph itself and are checked when a walk is restored
+    pub(crate) fn at(&self, index: u32) -> &FrozenSyntaxNode {
+        &self.nodes[index as usize]
+    }
+
+    pub(crate) fn edges(&self, node: &FrozenSyntaxNode) -> &[u32] {
+        &self.edges[node.edge_range()]
+    }
+
+    pub(crate) fn frequencies(&self, node: &FrozenSyntaxNode) -> &[f32] {
+        &self.frequencies[node.edge_range()]
+    }
+
+    // The literals a CH node prints, empty for every other node
+    pub(crate) fn texts(&self, node: &FrozenSyntaxNode) -> &[String] {
+        if node.typ != NodeType::CH {
+            return &[];
+        }
+        let start = node.print as usize;
+        self.texts
+            .get(start..start + node.print_len as usize)
+            .unwrap_or(&[])
+    }
+
+    // Option on the shortest way to the end of the rule. Ties go to fewer steps, so
+    // following it always makes progress.
+    pub(crate) fn shortest_option(&self, node: &FrozenSyntaxNode) -> usize {
+        let mut best = 0;
+        let mut best_cost = (u32::MAX, u32::MAX);
+        for (i, &edge) in self.edges(node).iter().enumerate() {
+            let next = self.at(edge);
+            if (next.min_tokens, next.min_steps) < best_cost {
+                best_cost = (next.min_tokens, next.min_steps);
+                best = i;
+            }
+        }
+        best
+    }
+
+    // Hashes nodes, edges, weights and what gets printed in index order, so the same grammar
+    // source always gives the same value and any change to what a walk could do gives another.
+    pub(crate) fn compute_fingerprint(&self) -> u64 {
+        let mut out = Writer::new();
+
+        for node in &self.nodes {
+            out.u32(node.id);
+            out.u8(node.typ.clone() as u8);
+            out.u32(node.pointer);
+            out.u32(node.callee);
+            out.u32(node.edge_count);
+            for (&edge, &freq) in self.edges(node).iter().zip(self.frequencies(node)) {
+                out.u32(edge);
+                out.f32(freq);
+            }
+            match node.typ {
+                NodeType::CH => {
+                    out.u32(node.print_len);
+                    for text in self.texts(node) {
+                        out.str(text);
                     }
-                    _ => {}
                 }
-
-                if current.options.is_empty() {
-                    return Ok(result);
+                NodeType::RX => self.regexer.encode_pattern(node.print, &mut out),
+                NodeType::REPEAT => {
+                    if let Some(repeat) = self.repeats.get(node.print as usize) {
+                        out.u32(repeat.min);
+                        out.u8(repeat.max.is_some() as u8);
+                        out.u32(repeat.max.unwrap_or(0));
+                        match &repeat.count {
+                            Some(count) => {
+                                out.u8(1);
+                                count.encode(&mut out);
+                            }
+                            None => out.u8(0),
+                        }
+                    }
                 }
-
-                let value = prng.random() as f32;
-                let index = match current
-                    .cumulative_frequency
-                    .iter()
-                    .position(|&x| x >= value)
-                {
-                    Some(i) => i,
-                    None => current.cumulative_frequency.len() - 1,
-                };
-
-                current_id = current.options[index].node.id;
+                _ => {}
             }
-        } else {
-            Err("Could not find starting node")
         }
+
+        let mut names: Vec<_> = self.name_map.iter().collect();
+        names.sort();
+        for (name, id) in names {
+            out.str(name);
+            out.u32(*id);
+        }
+
+        let mut hash = Fnv64::new();
+        hash.write(&out.buf);
+        hash.finish()
     }
 }
 // Helper function to handle escape sequences
-fn unescape_string(s: &str) -> String {
+pub(crate) fn unescape_string(s: &str) -> String {
     let mut result = String::with_capacity(s.len());
     let mut chars = s.chars();
 
@@ -0,0 +1,61 @@
+use crate::core::{
+    error::ResrapError,
+    frozen_graph::{FrozenSyntaxGraph, Termination},
+    prng::PRNG,
+    walker::{Step, Walker},
+};
+
+/// Lazily generated tokens from a frozen graph.
+/// There is no token count: the iterator runs until the start rule is done (forever with `^`),
+/// so bound it with `take`, `take_while` and friends. A seed yields the same tokens in the same
+/// order as `walk_graph`.
+pub struct Generator<'g> {
+    walker: Walker<'g>,
+    error: Option<ResrapError>,
+}
+
+impl<'g> Generator<'g> {
+    pub fn new(
+        graph: &'g FrozenSyntaxGraph,
+        prng: PRNG,
+        start: String,
+    ) -> Result<Self, ResrapError> {
+        Ok(Generator {
+            walker: Walker::new(graph, prng, start, usize::MAX, Termination::Truncate)?,
+            error: None,
+        })
+    }
+
+    /// The error that ended the iteration early, if any.
+    pub fn error(&self) -> Option<&ResrapError> {
+        self.error.as_ref()
+    }
+}
+
+impl Iterator for Generator<'_> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        if self.error.is_some() {
+            return None;
+        }
+        loop {
+            match self.walker.step() {
+                Ok(Step::Emit(text, _)) => return Some(text),
+                Ok(Step::Done) => return None,
+                Ok(Step::Enter(_) | Step::Exit) => {}
+                Err(err) => {
+                    self.error = Some(err);
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+impl FrozenSyntaxGraph {
+    /// Starts a lazy walk from `start`, see `Generator`.
+    pub fn generator(&self, prng: PRNG, start: String) -> Result<Generator<'_>, ResrapError> {
+        Generator::new(self, prng, start)
+    }
+}
@@ -1,10 +1,11 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     sync::{Arc, Mutex},
 };
 
 use crate::core::{
-    frozen_graph::{FrozenSyntaxEdge, FrozenSyntaxGraph, FrozenSyntaxNode},
+    frozen_graph::{FrozenSyntaxGraph, FrozenSyntaxNode, NO_INDEX, Repeat, unescape_string},
+    optimize,
     regex::Regexer,
 };
 #[derive(Clone)]
@@ -12,6 +13,8 @@ pub struct SyntaxGraph {
     pub node_ref: HashMap<u32, Arc<Mutex<SyntaxNode>>>,
     pub name_map: HashMap<String, u32>,
     pub print_map: HashMap<u32, String>,
+    /// Bounds of every REPEAT node.
+    pub repeat_map: HashMap<u32, Repeat>,
     pub regexer: Regexer,
 }
 
@@ -26,6 +29,10 @@ pub struct SyntaxEdge {
     pub probability: f32,
     pub node: Arc<Mutex<SyntaxNode>>,
 }
+// ID to (type, rule it calls, IDs of its edges), see `completions`
+type Shape = HashMap<u32, (NodeType, u32, Vec<u32>)>;
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Debug, PartialEq)]
 pub enum NodeType {
     START,
@@ -36,13 +43,34 @@ pub enum NodeType {
     RX,
     POINTER,
     IDK,
+    // Counted loop of `{m,n}`, goes into its body on the first edge and leaves on the second
+    REPEAT,
+}
+impl NodeType {
+    // Inverse of `as u8`, for reading graphs back from bytes
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        const ALL: [NodeType; 9] = [
+            NodeType::START,
+            NodeType::HEADER,
+            NodeType::JUMP,
+            NodeType::END,
+            NodeType::CH,
+            NodeType::RX,
+            NodeType::POINTER,
+            NodeType::IDK,
+            NodeType::REPEAT,
+        ];
+        ALL.into_iter().find(|typ| typ.clone() as u8 == tag)
+    }
 }
+
 impl SyntaxGraph {
     pub fn new() -> Self {
         SyntaxGraph {
             node_ref: HashMap::new(),
             name_map: HashMap::new(),
             print_map: HashMap::new(),
+            repeat_map: HashMap::new(),
             regexer: Regexer::new(),
         }
     }
@@ -78,9 +106,9 @@ impl SyntaxGraph {
             }
 
             // Build cumulative frequency
-            for i in 0..mcf.len() {
-                mcf[i] = cf + mcf[i] / sum;
-                cf = mcf[i];
+            for p in mcf.iter_mut() {
+                *p = cf + *p / sum;
+                cf = *p;
             }
 
             node_guard.cumulative_frequency = mcf;
@@ -91,59 +119,197 @@ impl SyntaxGraph {
         }
     }
 
-    pub fn freeze(self) -> FrozenSyntaxGraph {
-        // Step 1: Create all nodes (no edges)
-        let mut frozen_nodes: HashMap<u32, Arc<FrozenSyntaxNode>> = HashMap::new();
+    /// Shortest way from every node to the end of its rule, as (tokens printed, nodes visited).
+    /// A reference costs the shortest derivation of the rule it points to, and a counted loop
+    /// goes round its body as often as it has to before leaving. Going round again on the way
+    /// back from the body is costed as entering the loop afresh, which overestimates, never under.
+    /// Nodes that can never reach the end of their rule are left out.
+    pub fn completions(&self) -> HashMap<u32, (u32, u32)> {
+        // Copy the shape out once so the fixpoint below doesn't keep locking
+        let shape: Shape = self
+            .node_ref
+            .iter()
+            .map(|(&id, node)| {
+                let node = node.lock().unwrap();
+                let next = node
+                    .options
+                    .iter()
+                    .map(|edge| edge.node.lock().unwrap().id)
+                    .collect();
+                (id, (node.typ.clone(), node.pointer, next))
+            })
+            .collect();
 
-        for (&id, node_arc) in &self.node_ref {
-            let node_guard = node_arc.lock().unwrap();
-            frozen_nodes.insert(
-                id,
-                Arc::new(FrozenSyntaxNode {
-                    id: node_guard.id,
-                    typ: node_guard.typ.clone(),
-                    pointer: node_guard.pointer,
-                    cumulative_frequency: node_guard.cumulative_frequency.clone(),
-                    options: vec![], // fill later
-                }),
-            );
+        let ends: HashMap<u32, (u32, u32)> = shape
+            .iter()
+            .filter(|(_, (typ, _, _))| *typ == NodeType::END)
+            .map(|(&id, _)| (id, (0, 0)))
+            .collect();
+
+        // What a loop costs depends on the shortest way round its body, which depends on
+        // everything else. Both only ever shrink, so settle them in turns until neither moves.
+        let mut laps = HashMap::new();
+        loop {
+            let best = self.settle(&shape, ends.clone(), None, &laps);
+            let mut changed = false;
+            for &id in self.repeat_map.keys() {
+                let Some(&body) = shape.get(&id).and_then(|(_, _, next)| next.first()) else {
+                    continue;
+                };
+                let around = self.settle(&shape, HashMap::from([(id, (0, 0))]), Some(&best), &laps);
+                if let Some(&lap) = around.get(&body)
+                    && laps.insert(id, lap) != Some(lap)
+                {
+                    changed = true;
+                }
+            }
+            if !changed {
+                return best;
+            }
+        }
+    }
+
+    // Shortest way from every node to one already in `best`. Calls cost what `rules` says,
+    // or what `best` says when there is no `rules`. `laps` is the shortest way round each loop.
+    fn settle(
+        &self,
+        shape: &Shape,
+        mut best: HashMap<u32, (u32, u32)>,
+        rules: Option<&HashMap<u32, (u32, u32)>>,
+        laps: &HashMap<u32, (u32, u32)>,
+    ) -> HashMap<u32, (u32, u32)> {
+        let stops: HashSet<u32> = best.keys().copied().collect();
+        let add = |(tokens, steps): (u32, u32), own: (u32, u32)| {
+            (tokens.saturating_add(own.0), steps.saturating_add(own.1))
+        };
+
+        // Every node costs at least one step, so there are no free cycles and this settles
+        loop {
+            let mut changed = false;
+            for (&id, (typ, pointer, next)) in shape {
+                if stops.contains(&id) {
+                    continue;
+                }
+                let candidate = match typ {
+                    NodeType::END => continue,
+                    NodeType::REPEAT => {
+                        let Some(&exit) = next.get(1).and_then(|exit| best.get(exit)) else {
+                            continue;
+                        };
+                        let min = self.repeat_map.get(&id).map_or(0, |r| r.min);
+                        if min == 0 {
+                            Some(add(exit, (0, 1)))
+                        } else {
+                            laps.get(&id).map(|&(tokens, steps)| {
+                                let own = (tokens.saturating_mul(min), steps.saturating_mul(min));
+                                add(exit, add(own, (0, 1)))
+                            })
+                        }
+                    }
+                    _ => {
+                        let own = match typ {
+                            NodeType::CH | NodeType::RX => (1, 1),
+                            NodeType::POINTER => match rules.unwrap_or(&best).get(pointer) {
+                                Some(&(tokens, steps)) => (tokens, steps.saturating_add(1)),
+                                None => continue,
+                            },
+                            _ => (0, 1),
+                        };
+                        next.iter()
+                            .filter_map(|n| best.get(n))
+                            .map(|&cost| add(cost, own))
+                            .min()
+                    }
+                };
+                if let Some(candidate) = candidate
+                    && best.get(&id).is_none_or(|&old| candidate < old)
+                {
+                    best.insert(id, candidate);
+                    changed = true;
+                }
+            }
+            if !changed {
+                return best;
+            }
         }
+    }
 
-        // Step 2: Rebuild with filled options
-        let mut filled_nodes: HashMap<u32, Arc<FrozenSyntaxNode>> = HashMap::new();
+    pub fn freeze(self) -> FrozenSyntaxGraph {
+        let completions = self.completions();
+        let completion = |id: u32| {
+            completions
+                .get(&id)
+                .copied()
+                .unwrap_or((u32::MAX, u32::MAX))
+        };
+
+        // Sorted by ID, so the same grammar always gets the same layout
+        let mut ids: Vec<u32> = self.node_ref.keys().copied().collect();
+        ids.sort_unstable();
+        let index: HashMap<u32, u32> = ids
+            .iter()
+            .enumerate()
+            .map(|(i, &id)| (id, i as u32))
+            .collect();
+
+        let mut nodes = Vec::with_capacity(ids.len());
+        let mut edges = vec![];
+        let mut frequencies = vec![];
+        let mut texts = vec![];
+        let mut repeats = vec![];
+        for &id in &ids {
+            let node = self.node_ref[&id].lock().unwrap();
 
-        for (&id, node_arc) in &self.node_ref {
-            let node_guard = node_arc.lock().unwrap();
+            let first_edge = edges.len() as u32;
+            for edge in &node.options {
+                edges.push(index[&edge.node.lock().unwrap().id]);
+            }
+            frequencies.extend_from_slice(&node.cumulative_frequency);
 
-            let options = node_guard
-                .options
-                .iter()
-                .map(|edge| {
-                    let target_node = frozen_nodes.get(&edge.node.lock().unwrap().id).unwrap();
-                    FrozenSyntaxEdge {
-                        node: Arc::clone(target_node),
+            let print = match (&node.typ, self.print_map.get(&id)) {
+                (NodeType::CH, Some(text)) => {
+                    texts.push(unescape_string(text));
+                    texts.len() as u32 - 1
+                }
+                (NodeType::RX, Some(text)) => self.regexer.pattern_index(text).unwrap_or(NO_INDEX),
+                (NodeType::REPEAT, _) => match self.repeat_map.get(&id) {
+                    Some(repeat) => {
+                        repeats.push(repeat.clone());
+                        repeats.len() as u32 - 1
                     }
-                })
-                .collect::<Vec<_>>();
+                    None => NO_INDEX,
+                },
+                _ => NO_INDEX,
+            };
 
-            filled_nodes.insert(
+            nodes.push(FrozenSyntaxNode {
                 id,
-                Arc::new(FrozenSyntaxNode {
-                    id: node_guard.id,
-                    typ: node_guard.typ.clone(),
-                    pointer: node_guard.pointer,
-                    cumulative_frequency: node_guard.cumulative_frequency.clone(),
-                    options,
-                }),
-            );
+                typ: node.typ.clone(),
+                pointer: node.pointer,
+                callee: index.get(&node.pointer).copied().unwrap_or(NO_INDEX),
+                print,
+                print_len: 1,
+                first_edge,
+                edge_count: node.options.len() as u32,
+                min_tokens: completion(id).0,
+                min_steps: completion(id).1,
+            });
         }
 
-        FrozenSyntaxGraph {
-            node_ref: filled_nodes,
+        let mut frozen = FrozenSyntaxGraph {
+            nodes,
+            edges,
+            frequencies,
+            texts,
+            repeats,
+            index,
             name_map: self.name_map,
-            print_map: self.print_map,
             regexer: self.regexer,
-        }
+            fingerprint: 0,
+        };
+        optimize::optimize(&mut frozen);
+        frozen.fingerprint = frozen.compute_fingerprint();
+        frozen
     }
 }
 
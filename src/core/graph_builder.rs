@@ -1,49 +1,96 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
 use crate::core::{
-    frozen_graph::FrozenSyntaxGraph, parser::Parser, regex::Regexer, scanner::Scanner,
+    analysis::{self, Analysis},
+    diagnostic::Diagnostic,
+    error::ResrapError,
+    file::LangConfig,
+    frozen_graph::FrozenSyntaxGraph,
+    parser::Parser,
+    regex::Regexer,
+    scanner::{Scanner, TokenType},
 };
 
 pub struct GraphBuilder {
     pars: Parser,
     frozen: FrozenSyntaxGraph,
+    analysis: Analysis,
 }
 impl GraphBuilder {
-    pub fn new() -> Self {
+    pub fn new(config: LangConfig) -> Self {
+        let mut pars = Parser::new();
+        pars.config = config;
         GraphBuilder {
-            pars: Parser::new(),
+            pars,
             frozen: FrozenSyntaxGraph {
-                node_ref: HashMap::new(),
+                nodes: vec![],
+                edges: vec![],
+                frequencies: vec![],
+                texts: vec![],
+                repeats: vec![],
+                index: HashMap::new(),
                 name_map: HashMap::new(),
-                print_map: HashMap::new(),
                 regexer: Regexer::new(),
+                fingerprint: 0,
             },
+            analysis: Analysis::default(),
         }
     }
-    pub fn take_graph(self) -> FrozenSyntaxGraph {
-        self.frozen
+    pub fn take(self) -> (FrozenSyntaxGraph, Analysis) {
+        (self.frozen, self.analysis)
     }
-    pub fn start_generation(&mut self, grammar: String) -> Result<(), String> {
-        let sc = Scanner::new(grammar);
-        let (tokens, errors) = sc.scan();
+    /// Builds the graph for `grammar`. `file` only labels the spans in diagnostics.
+    pub fn start_generation(
+        &mut self,
+        grammar: String,
+        file: Option<Arc<str>>,
+    ) -> Result<(), ResrapError> {
+        let sc = Scanner::new(&grammar, file);
+        let (mut tokens, scan_errors) = sc.scan();
 
-        if !errors.is_empty() {
-            Err(String::from("Scan Error"))
-        } else {
-            self.pars.tokens = tokens;
+        // A scan error swallows the rest of the input, so only statements that were closed
+        // before it can be parsed without reporting noise
+        if let Some(first) = scan_errors.first() {
+            let cutoff = first.span().start;
+            let keep = tokens
+                .iter()
+                .rposition(|t| t.typ == TokenType::Padding && t.span.start < cutoff)
+                .map_or(0, |i| i + 1);
+            tokens.truncate(keep);
+        }
+
+        self.pars.tokens = tokens;
 
-            self.pars.parse_grammar();
-            self.pars.graph.normalize();
-            self.pars.graph.print_map = self.pars.charmap.clone();
-            self.pars.graph.name_map = self.pars.name_map.clone();
-            self.pars.graph.regexer = self.pars.regexhandler.clone();
-            self.frozen = self.pars.graph.clone().freeze();
+        self.pars.parse_grammar();
+        self.pars.graph.normalize();
+        self.pars.graph.print_map = self.pars.charmap.clone();
+        self.pars.graph.name_map = self.pars.name_map.clone();
+        self.pars.graph.regexer = self.pars.regexhandler.clone();
+        self.frozen = self.pars.graph.clone().freeze();
 
-            if !self.pars.errors.is_empty() {
-                Err(String::from(&self.pars.errors[0]))
-            } else {
-                return Ok(());
-            }
+        // Rules past a scan error were never seen, so references to them prove nothing
+        if scan_errors.is_empty() {
+            let undefined = analysis::undefined_rules(&self.pars);
+            self.pars.errors.extend(undefined);
+            self.pars.errors.sort_by_key(|d| d.span.start);
+        }
+        self.analysis = Analysis::new(&self.pars, &self.frozen);
+
+        let attach = |diags: Vec<Diagnostic>| {
+            diags
+                .into_iter()
+                .map(|d| d.with_source(&grammar))
+                .collect::<Vec<_>>()
+        };
+        if !scan_errors.is_empty() {
+            let mut diags = self.pars.errors.clone();
+            diags.extend(scan_errors.into_iter().map(Diagnostic::from));
+            diags.sort_by_key(|d| d.span.start);
+            Err(ResrapError::Scan(attach(diags)))
+        } else if !self.pars.errors.is_empty() {
+            Err(ResrapError::Parse(attach(self.pars.errors.clone())))
+        } else {
+            Ok(())
         }
     }
 }
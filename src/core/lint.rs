@@ -0,0 +1,198 @@
+use std::{collections::HashSet, sync::Arc};
+
+use crate::core::{
+    diagnostic::{Diagnostic, Span},
+    graph::NodeType,
+    parser::Parser,
+};
+
+/// Which construct a `<...>` annotation was attached to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnnotationKind {
+    Element,   // 'x'<p>, [a-z]<p>, rule<p>
+    Option,    // |<p>
+    Maybe,     // ?<p>
+    OneOrMore, // +<p>
+    AnyNo,     // *<p>
+    Repeat,    // {m,n}<p>
+    Ignored,   // nothing before it takes a weight
+}
+
+/// A probability annotation as the parser applied it.
+#[derive(Debug, Clone)]
+pub struct Annotation {
+    pub rule: u32,
+    pub span: Span,
+    pub value: f32,
+    pub kind: AnnotationKind,
+    /// Node whose outgoing edge carries the value.
+    pub node: u32,
+}
+
+/// A `^` back edge from `node` to `target`.
+#[derive(Debug, Clone)]
+pub struct InfiniteLoop {
+    pub rule: u32,
+    pub span: Span,
+    pub node: u32,
+    pub target: u32,
+}
+
+/// Looks for probability annotations that don't do what they seem to.
+/// Runs on the parser's graph before normalization, while raw weights are still around.
+pub fn lint(parser: &Parser) -> Vec<Diagnostic> {
+    let rule_name = |id: u32| {
+        parser
+            .rev_name_map
+            .get(&id)
+            .map_or("?", String::as_str)
+            .to_string()
+    };
+    // (edge count, weight sum) of a node's outgoing edges
+    let weights = |id: u32| {
+        parser.graph.node_ref.get(&id).map(|node| {
+            let node = node.lock().unwrap();
+            let sum: f32 = node.options.iter().map(|e| e.probability).sum();
+            (node.options.len(), sum)
+        })
+    };
+    let mut diags = vec![];
+
+    let mut zero_sum_seen = HashSet::new();
+    for ann in &parser.annotations {
+        let rule = rule_name(ann.rule);
+        match ann.kind {
+            AnnotationKind::Ignored => {
+                diags.push(Diagnostic::warning(
+                    format!(
+                        "Annotation in rule '{}' has no effect, nothing before it takes a weight",
+                        rule
+                    ),
+                    ann.span.clone(),
+                ));
+                continue;
+            }
+            AnnotationKind::Maybe
+            | AnnotationKind::OneOrMore
+            | AnnotationKind::AnyNo
+            | AnnotationKind::Repeat
+                if ann.value > 1.0 =>
+            {
+                diags.push(Diagnostic::warning(
+                    format!(
+                        "Probability {} on a repetition in rule '{}' is outside [0, 1]",
+                        ann.value, rule
+                    ),
+                    ann.span.clone(),
+                ));
+                continue; // the weights it broke follow from this
+            }
+            _ => {}
+        }
+
+        match weights(ann.node) {
+            Some((_, sum)) if sum <= 0.0 => {
+                if !zero_sum_seen.insert(ann.node) {
+                    continue;
+                }
+                diags.push(Diagnostic::warning(
+                    format!(
+                        "Every branch here in rule '{}' has weight 0, the choice is undefined",
+                        rule
+                    ),
+                    ann.span.clone(),
+                ));
+            }
+            Some((1, _)) => {
+                let hint = if ann.kind == AnnotationKind::Option {
+                    ", '|' weights the end of the alternative before it"
+                } else {
+                    ""
+                };
+                diags.push(Diagnostic::warning(
+                    format!(
+                        "Annotation in rule '{}' has no effect, it weights the only choice{}",
+                        rule, hint
+                    ),
+                    ann.span.clone(),
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    for lp in &parser.loops {
+        let Some(node) = parser.graph.node_ref.get(&lp.node) else {
+            continue;
+        };
+        // A loop on a rule's END is left by returning to the caller, or by a Complete or
+        // Bounded walk ending there, only a truncated walk from this rule keeps going round
+        if node.lock().unwrap().typ == NodeType::END {
+            continue;
+        }
+        // Compare by pointer, a node may loop onto itself and its lock is already held
+        let targets: Vec<_> = parser
+            .loops
+            .iter()
+            .filter(|other| other.node == lp.node)
+            .filter_map(|other| parser.graph.node_ref.get(&other.target))
+            .collect();
+        let exit: f32 = node
+            .lock()
+            .unwrap()
+            .options
+            .iter()
+            .filter(|e| !targets.iter().any(|t| Arc::ptr_eq(t, &e.node)))
+            .map(|e| e.probability)
+            .sum();
+        if exit <= 0.0 {
+            diags.push(Diagnostic::warning(
+                format!(
+                    "'^' loop in rule '{}' has weight 0 on every way out, only a Complete or \
+                     Bounded walk past its target ever leaves it",
+                    rule_name(lp.rule)
+                ),
+                lp.span.clone(),
+            ));
+        }
+    }
+
+    diags.sort_by_key(|d| d.span.start);
+    diags
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Resrap;
+
+    fn lint(grammar: &str) -> Vec<String> {
+        let mut resrap = Resrap::new();
+        resrap
+            .parse_grammar("g".to_string(), grammar.to_string())
+            .unwrap();
+        resrap
+            .lint_grammar("g")
+            .unwrap()
+            .into_iter()
+            .map(|diag| diag.message)
+            .collect()
+    }
+
+    #[test]
+    fn rule_level_loops_are_fine() {
+        let grammar = "program : (header+<0.4>) function^; header: 'h'; function: 'f';";
+        assert_eq!(lint(grammar), Vec::<String>::new());
+        assert_eq!(lint("s: 'x' ('a' | 'b')^;"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn loop_left_only_at_weight_zero_warns() {
+        let diags = lint("s: (('a')^) 'c'<0>;");
+        assert_eq!(diags.len(), 1);
+        assert!(
+            diags[0].contains("weight 0 on every way out"),
+            "{}",
+            diags[0]
+        );
+    }
+}
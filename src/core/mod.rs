@@ -1,8 +1,29 @@
+mod analysis;
+pub mod annotated;
+#[cfg(feature = "rayon")]
+pub mod batch;
+pub mod bias;
+pub mod build;
+mod codec;
+mod compiled;
+pub mod derivation;
+pub mod diagnostic;
+pub mod distribution;
+pub mod error;
 pub mod file;
 pub mod frozen_graph;
+pub mod generator;
 mod graph;
 mod graph_builder;
+mod lint;
+mod optimize;
 mod parser;
+pub mod pool;
 pub mod prng;
 mod regex;
+mod regex_parser;
 mod scanner;
+pub mod session;
+#[cfg(feature = "tokio")]
+pub mod stream;
+pub mod walker;
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use crate::core::{
+    frozen_graph::{FrozenSyntaxGraph, FrozenSyntaxNode, NO_INDEX},
+    graph::NodeType,
+};
+
+/// Shrinks a freshly frozen graph without changing what walks over it can print, or how likely
+/// each output is.
+///
+/// - Edges into START, HEADER, JUMP and IDK nodes with a single way out skip straight to where
+///   that way leads. They print nothing and always go the same way, so skipping them changes
+///   nothing but the step count. Chains that loop on themselves are left alone.
+/// - A literal that can only be followed by another literal becomes one node printing both.
+///   The walker still emits them as separate tokens, so token counts don't move.
+/// - Nodes nothing reaches any more are dropped and the rest are packed again.
+///
+/// Completion lengths are kept: skipped nodes print nothing, so `min_tokens` still holds, and
+/// `min_steps` only breaks ties and still shrinks along every edge.
+pub fn optimize(graph: &mut FrozenSyntaxGraph) {
+    skip_forwarders(graph);
+    fold_literals(graph);
+    compact(graph);
+}
+
+fn is_forwarder(node: &FrozenSyntaxNode) -> bool {
+    matches!(
+        node.typ,
+        NodeType::START | NodeType::HEADER | NodeType::JUMP | NodeType::IDK
+    ) && node.edge_count == 1
+}
+
+fn skip_forwarders(graph: &mut FrozenSyntaxGraph) {
+    // Where a walk arriving at each node ends up once it is past every forwarder
+    let resolved: Vec<u32> = (0..graph.nodes.len() as u32)
+        .map(|start| {
+            let mut chain = vec![];
+            let mut current = start;
+            while is_forwarder(graph.at(current)) {
+                if chain.contains(&current) {
+                    // A cycle of forwarders never gets anywhere, leave it as it is
+                    return start;
+                }
+                chain.push(current);
+                current = graph.edges[graph.at(current).first_edge as usize];
+            }
+            current
+        })
+        .collect();
+
+    for edge in &mut graph.edges {
+        *edge = resolved[*edge as usize];
+    }
+    for node in &mut graph.nodes {
+        if node.callee != NO_INDEX {
+            node.callee = resolved[node.callee as usize];
+        }
+    }
+}
+
+fn fold_literals(graph: &mut FrozenSyntaxGraph) {
+    let foldable = |node: &FrozenSyntaxNode| node.typ == NodeType::CH && node.print != NO_INDEX;
+    // Runs are read off the unfolded graph, nodes already folded would chain twice
+    let original: Vec<(u32, u32, u32)> = graph
+        .nodes
+        .iter()
+        .map(|n| (n.print, n.first_edge, n.edge_count))
+        .collect();
+
+    for start in 0..graph.nodes.len() {
+        if !foldable(&graph.nodes[start]) {
+            continue;
+        }
+        let mut run = vec![start as u32];
+        let mut last = start;
+        loop {
+            let (_, first_edge, edge_count) = original[last];
+            if edge_count != 1 {
+                break;
+            }
+            let next = graph.edges[first_edge as usize];
+            if !foldable(graph.at(next)) || run.contains(&next) {
+                break;
+            }
+            run.push(next);
+            last = next as usize;
+        }
+        if run.len() < 2 {
+            continue;
+        }
+
+        let print = graph.texts.len() as u32;
+        for &member in &run {
+            let text = graph.texts[original[member as usize].0 as usize].clone();
+            graph.texts.push(text);
+        }
+        let node = &mut graph.nodes[start];
+        node.print = print;
+        node.print_len = run.len() as u32;
+        node.first_edge = original[last].1;
+        node.edge_count = original[last].2;
+    }
+}
+
+fn compact(graph: &mut FrozenSyntaxGraph) {
+    let mut keep = vec![false; graph.nodes.len()];
+    let mut stack: Vec<u32> = graph
+        .nodes
+        .iter()
+        .enumerate()
+        .filter(|(_, n)| matches!(n.typ, NodeType::START | NodeType::HEADER))
+        .map(|(i, _)| i as u32)
+        .collect();
+    while let Some(index) = stack.pop() {
+        if std::mem::replace(&mut keep[index as usize], true) {
+            continue;
+        }
+        let node = graph.at(index);
+        stack.extend_from_slice(graph.edges(node));
+        if node.callee != NO_INDEX {
+            stack.push(node.callee);
+        }
+    }
+
+    let mut remap = vec![NO_INDEX; graph.nodes.len()];
+    let mut next = 0;
+    for (old, &kept) in keep.iter().enumerate() {
+        if kept {
+            remap[old] = next;
+            next += 1;
+        }
+    }
+
+    let old_nodes = std::mem::take(&mut graph.nodes);
+    let old_edges = std::mem::take(&mut graph.edges);
+    let old_frequencies = std::mem::take(&mut graph.frequencies);
+    let old_texts = std::mem::take(&mut graph.texts);
+    let mut index = HashMap::new();
+    for (old, mut node) in old_nodes.into_iter().enumerate() {
+        if !keep[old] {
+            continue;
+        }
+        let range = node.edge_range();
+        node.first_edge = graph.edges.len() as u32;
+        graph
+            .edges
+            .extend(old_edges[range.clone()].iter().map(|&e| remap[e as usize]));
+        graph.frequencies.extend_from_slice(&old_frequencies[range]);
+
+        if node.typ == NodeType::CH && node.print != NO_INDEX {
+            let texts = node.print as usize..(node.print + node.print_len) as usize;
+            node.print = graph.texts.len() as u32;
+            graph.texts.extend_from_slice(&old_texts[texts]);
+        }
+        if node.callee != NO_INDEX {
+            node.callee = remap[node.callee as usize];
+        }
+        index.insert(node.id, graph.nodes.len() as u32);
+        graph.nodes.push(node);
+    }
+    graph.index = index;
+}
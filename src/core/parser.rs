@@ -1,5 +1,11 @@
 use crate::core::{
+    bias::{BiasProfile, CharBias},
+    diagnostic::{Diagnostic, Span},
+    distribution::Distribution,
+    file::LangConfig,
+    frozen_graph::Repeat,
     graph::{NodeType, SyntaxGraph, SyntaxNode},
+    lint::{Annotation, AnnotationKind, InfiniteLoop},
     regex::Regexer,
     scanner::{Token, TokenType},
 };
@@ -8,18 +14,48 @@ use std::{
     sync::{Arc, Mutex},
 };
 
+// (group start, group end) handed back to the enclosing rule
+type RuleEnds = (
+    Option<Arc<Mutex<SyntaxNode>>>,
+    Option<Arc<Mutex<SyntaxNode>>>,
+);
+
+// What a `<regex>`, `<len=...>` or `<bias=...>` after a `[...]` sets
+enum RegexOption {
+    Syntax,
+    Length(Distribution),
+    Bias(Arc<dyn CharBias>),
+}
+
+// Where the last element or group began, for a `{m,n}` after it to wrap it in a loop
+#[derive(Clone, Copy)]
+struct ElementStart {
+    // Edges the node before it had, the ones added after lead into it
+    edges: usize,
+    // Nodes it created got IDs above this
+    func_ptr: u32,
+    // Annotations noted before it
+    annotations: usize,
+}
+
 pub struct Parser {
     pub func_ptr: u32,
     pub print_ptr: u32,
     pub name_map: HashMap<String, u32>,
     pub rev_name_map: HashMap<u32, String>,
     pub def_check: HashMap<u32, bool>,
+    pub def_spans: HashMap<u32, Span>,
+    pub ref_spans: Vec<(u32, Span)>,
+    pub annotations: Vec<Annotation>,
+    pub loops: Vec<InfiniteLoop>,
+    pub current_rule: u32,
     pub charmap: HashMap<u32, String>,
     pub tokens: Vec<Token>,
-    pub errors: Vec<String>,
+    pub errors: Vec<Diagnostic>,
     pub index: usize,
     pub graph: SyntaxGraph,
     pub regexhandler: Regexer,
+    pub config: LangConfig,
 }
 
 impl Parser {
@@ -30,12 +66,18 @@ impl Parser {
             name_map: HashMap::new(),
             rev_name_map: HashMap::new(),
             def_check: HashMap::new(),
+            def_spans: HashMap::new(),
+            ref_spans: Vec::new(),
+            annotations: Vec::new(),
+            loops: Vec::new(),
+            current_rule: 0,
             charmap: HashMap::new(),
             tokens: Vec::new(),
             errors: Vec::new(),
             index: 0,
             graph: SyntaxGraph::new(),
             regexhandler: Regexer::new(),
+            config: LangConfig::default(),
         }
     }
 
@@ -53,18 +95,51 @@ impl Parser {
         &self.tokens[self.index]
     }
 
+    // span of the current token, or of the last one once input ran out
+    fn curr_span(&self) -> Span {
+        self.tokens
+            .get(self.index)
+            .or(self.tokens.last())
+            .map(|t| t.span.clone())
+            .unwrap_or_default()
+    }
+
+    fn error(&mut self, msg: impl Into<String>, span: Span) {
+        self.errors.push(Diagnostic::error(msg, span));
+    }
+
+    fn error_here(&mut self, msg: impl Into<String>) {
+        let span = self.curr_span();
+        self.error(msg, span);
+    }
+
     fn match_token(&self, word: TokenType, expected: &[TokenType]) -> bool {
         expected.contains(&word)
     }
 
+    // Consumes the current token if it matches, otherwise records the error and leaves it in place
     fn expect(&mut self, expected: &[TokenType], errmsg: &str) -> bool {
-        if !self.match_token(self.curr().typ, expected) {
-            self.errors.push(errmsg.to_string());
+        match self.tokens.get(self.index) {
+            Some(token) if self.match_token(token.typ, expected) => {
+                self.index += 1;
+                false
+            }
+            _ => {
+                self.error_here(errmsg);
+                true
+            }
+        }
+    }
+
+    // Skips past the next ';' so parsing can pick up again at the following statement
+    fn synchronize(&mut self) {
+        while self.index < self.tokens.len() {
+            let typ = self.curr().typ;
             self.index += 1;
-            return true;
+            if typ == TokenType::Padding {
+                return;
+            }
         }
-        self.index += 1;
-        false
     }
 
     fn get_index(&mut self, name: &str) -> u32 {
@@ -78,35 +153,40 @@ impl Parser {
         value
     }
 
+    /// Parses every statement, recovering from errors so that all of them end up in `errors`.
     pub fn parse_grammar(&mut self) {
         while self.index < self.tokens.len() {
-            self.parse_subject();
-            if !self.errors.is_empty() {
-                return; // Crash on errors for now
+            if !self.parse_subject() {
+                self.synchronize();
             }
         }
     }
 
-    fn parse_subject(&mut self) {
+    // Returns false if the statement header was malformed and the parser needs to resync
+    fn parse_subject(&mut self) -> bool {
         let subject = self.curr().clone();
 
         if self.expect(
             &[TokenType::Identifier],
             "Expected Subject at start of statement",
         ) {
-            return;
+            return false;
         }
         if self.expect(&[TokenType::Colon], "Expected Colon after Subject") {
-            return;
+            return false;
         }
 
         let id = self.get_index(&subject.text);
         if *self.def_check.get(&id).unwrap_or(&false) {
-            self.errors
-                .push(format!("Multiple definitions for {}", subject.text));
+            self.error(
+                format!("Multiple definitions for {}", subject.text),
+                subject.span.clone(),
+            );
         }
 
         self.def_check.insert(id, true);
+        self.def_spans.entry(id).or_insert(subject.span);
+        self.current_rule = id;
         let startnode = self.graph.force_get_node(0, NodeType::START); // assuming 0 is start
         let headernode = self.graph.force_get_node(id, NodeType::HEADER);
         {
@@ -116,43 +196,54 @@ impl Parser {
 
         if self.index > 0 && self.match_token(self.tokens[self.index - 1].typ, &[TokenType::Colon])
         {
+            // A broken body already leaves the parser at the next statement
             self.parse_rules(id, false);
         }
+        true
     }
 
-    fn parse_rules(
-        &mut self,
-        root: u32,
-        is_deep: bool,
-    ) -> (
-        Option<Arc<Mutex<SyntaxNode>>>,
-        Option<Arc<Mutex<SyntaxNode>>>,
-    ) {
+    // Returns None if the rule had to be abandoned; `index` is then at the start of the next statement
+    fn parse_rules(&mut self, root: u32, is_deep: bool) -> Option<RuleEnds> {
         let rootnode = self.graph.force_get_node(root, NodeType::IDK);
         let mut buffer_node = Arc::clone(&rootnode);
-        let mut end_node = self.graph.force_get_node(1, NodeType::END); // assuming 1 is end
         let mut start_buffer: Option<Arc<Mutex<SyntaxNode>>> = None;
+        let mut element: Option<ElementStart> = None;
+        // `(` that opened this group, already consumed by the caller
+        let open_span = self.tokens[self.index.saturating_sub(1)].span.clone();
 
-        if is_deep {
-            let ptr = self.get_func_ptr();
-            end_node = self.graph.force_get_node(ptr, NodeType::END);
-        }
+        // Every rule returns to its caller from its own END, so a top level `^` only loops
+        // the rule it was written in. A group just joins its alternatives back up.
+        let ptr = self.get_func_ptr();
+        let end_type = if is_deep {
+            NodeType::IDK
+        } else {
+            NodeType::END
+        };
+        let end_node = self.graph.force_get_node(ptr, end_type);
 
         loop {
             if self.index >= self.tokens.len() {
-                break;
+                if is_deep {
+                    self.error("Stray '('", open_span);
+                } else {
+                    self.error_here("Missing Semicolon");
+                }
+                return None;
             }
 
             match self.curr().typ {
                 TokenType::Identifier => {
+                    element = Some(self.element_start(&buffer_node));
                     let node = self.tokens[self.index].text.clone();
                     let pointer_id = self.get_index(&node);
+                    self.ref_spans
+                        .push((pointer_id, self.tokens[self.index].span.clone()));
                     let ptr = self.get_func_ptr();
                     let node = self.graph.force_get_node(ptr, NodeType::POINTER);
                     {
                         node.lock().unwrap().pointer = pointer_id;
                     }
-                    let probability = self.get_probability();
+                    let probability = self.get_probability(AnnotationKind::Element, &buffer_node);
                     {
                         buffer_node
                             .lock()
@@ -168,20 +259,44 @@ impl Parser {
                     buffer_node = jump_node;
                 }
                 TokenType::Character | TokenType::Regex => {
+                    element = Some(self.element_start(&buffer_node));
                     let index = self.get_print_ptr();
-                    self.charmap
-                        .insert(index, self.tokens[self.index].text.clone());
+                    let text = self.curr().text.clone();
 
-                    let node_type = if self.tokens[self.index].typ == TokenType::Character {
+                    let node_type = if self.curr().typ == TokenType::Character {
+                        self.charmap.insert(index, text);
                         NodeType::CH
                     } else {
-                        let text = self.curr().text.clone();
-                        self.regexhandler.cache_regex(&text);
+                        let span = self.curr_span();
+                        let mut length = self.config.regex_length.clone();
+                        let mut bias = self.config.bias.clone();
+                        let mut syntax = false;
+                        // The same regex with other options is another pattern
+                        let mut key = text.clone();
+                        while let Some((annotation, option)) = self.get_regex_option() {
+                            key.push_str(&format!("<{}>", annotation));
+                            match option {
+                                RegexOption::Syntax => syntax = true,
+                                RegexOption::Length(option) => length = Some(option),
+                                RegexOption::Bias(option) => bias = Some(option),
+                            }
+                        }
+                        let bias = bias.unwrap_or_else(|| Arc::new(BiasProfile::default()));
+                        if let Err(msg) = self.regexhandler.cache_regex(
+                            &key,
+                            &text,
+                            syntax,
+                            length,
+                            bias.as_ref(),
+                        ) {
+                            self.error(format!("Invalid regex, {}", msg), span);
+                        }
+                        self.charmap.insert(index, key);
                         NodeType::RX
                     };
 
                     let leafnode = self.graph.force_get_node(index, node_type);
-                    let probability = self.get_probability();
+                    let probability = self.get_probability(AnnotationKind::Element, &buffer_node);
                     {
                         buffer_node
                             .lock()
@@ -201,12 +316,17 @@ impl Parser {
                     buffer_node = jump_node;
                 }
                 TokenType::Colon => {
-                    self.errors.push("Missing Semicolon".to_string());
-                    return (None, None);
+                    // The identifier before this colon starts the next statement,
+                    // so the ';' belongs right before it
+                    let span = self.tokens[self.index.saturating_sub(2)].span.clone();
+                    self.error("Missing Semicolon", span);
+                    self.index -= 1;
+                    return None;
                 }
                 TokenType::Maybe => {
+                    element = None;
                     if let Some(ref sb) = start_buffer {
-                        let probability = self.get_probability();
+                        let probability = self.get_probability(AnnotationKind::Maybe, sb);
                         {
                             sb.lock()
                                 .unwrap()
@@ -214,9 +334,20 @@ impl Parser {
                         }
                     }
                 }
+                // With a length distribution these are counted loops like `{1,}` and `{0,}`
+                TokenType::OneOrMore | TokenType::AnyNo if self.distribution_follows() => {
+                    let min = (self.curr().typ == TokenType::OneOrMore) as u32;
+                    if let Some(exit) =
+                        self.repeat(element.take(), &start_buffer, &buffer_node, min, None)
+                    {
+                        buffer_node = exit;
+                    }
+                }
                 TokenType::OneOrMore => {
+                    element = None;
                     if let Some(ref sb) = start_buffer {
-                        let probability = self.get_probability();
+                        let probability =
+                            self.get_probability(AnnotationKind::OneOrMore, &buffer_node);
                         {
                             buffer_node
                                 .lock()
@@ -226,8 +357,9 @@ impl Parser {
                     }
                 }
                 TokenType::AnyNo => {
+                    element = None;
                     if let Some(ref sb) = start_buffer {
-                        let probability = self.get_probability();
+                        let probability = self.get_probability(AnnotationKind::AnyNo, sb);
                         {
                             sb.lock()
                                 .unwrap()
@@ -242,7 +374,8 @@ impl Parser {
                     }
                 }
                 TokenType::Option => {
-                    let probability = self.get_probability();
+                    element = None;
+                    let probability = self.get_probability(AnnotationKind::Option, &buffer_node);
                     {
                         buffer_node
                             .lock()
@@ -259,17 +392,19 @@ impl Parser {
                             .unwrap()
                             .add_edge(Arc::clone(&end_node), 1.0);
                     }
+                    self.index += 1;
                     if is_deep {
-                        self.errors.push("Stray '('".to_string());
+                        self.error("Stray '('", open_span);
+                        return None;
                     }
-                    self.index += 1;
-                    return (None, None);
+                    return Some((None, None));
                 }
                 TokenType::BracOpen => {
+                    element = Some(self.element_start(&buffer_node));
                     self.index += 1;
                     {
                         let buffer_id = buffer_node.lock().unwrap().id; // Lock acquired and immediately released
-                        let (new_start, new_end) = self.parse_rules(buffer_id, true);
+                        let (new_start, new_end) = self.parse_rules(buffer_id, true)?;
                         start_buffer = new_start;
                         if let Some(end) = new_end {
                             buffer_node = end;
@@ -284,38 +419,233 @@ impl Parser {
                                 .unwrap()
                                 .add_edge(Arc::clone(&end_node), 1.0);
                         }
-                        return (Some(rootnode), Some(end_node));
+                        return Some((Some(rootnode), Some(end_node)));
                     }
-                    self.errors.push("Stray ')' found".to_string());
+                    self.error_here("Stray ')' found");
                 }
                 TokenType::Infinite => {
+                    element = None;
                     if let Some(ref sb) = start_buffer {
                         end_node.lock().unwrap().add_edge(Arc::clone(sb), 1.0);
+                        self.loops.push(InfiniteLoop {
+                            rule: self.current_rule,
+                            span: self.curr_span(),
+                            node: end_node.lock().unwrap().id,
+                            target: sb.lock().unwrap().id,
+                        });
                     }
                 }
-                _ => {}
+                TokenType::Repeat => {
+                    let from = element.take();
+                    match parse_bounds(&self.curr().text) {
+                        Some((min, max)) if max.is_some_and(|max| max < min) => {
+                            self.error_here(
+                                "Repetition range is empty, its minimum is above its maximum",
+                            );
+                        }
+                        Some((min, max)) => {
+                            if let Some(exit) =
+                                self.repeat(from, &start_buffer, &buffer_node, min, max)
+                            {
+                                buffer_node = exit;
+                            }
+                        }
+                        None => self.error_here("Expected {n}, {m,n} or {m,} after element"),
+                    }
+                }
+                TokenType::Probability => {
+                    // Nothing before it takes a weight
+                    self.annotations.push(Annotation {
+                        rule: self.current_rule,
+                        span: self.curr_span(),
+                        value: 0.0,
+                        kind: AnnotationKind::Ignored,
+                        node: 0,
+                    });
+                }
+            }
+            self.index += 1;
+        }
+    }
+
+    fn element_start(&self, before: &Arc<Mutex<SyntaxNode>>) -> ElementStart {
+        ElementStart {
+            edges: before.lock().unwrap().options.len(),
+            func_ptr: self.func_ptr,
+            annotations: self.annotations.len(),
+        }
+    }
+
+    // Wraps the element from `start` to `last` in a counted loop and returns the node after it.
+    // The element is moved into a body of its own, so jumps back to its start from inside,
+    // like a `+` on its first part, go round the body instead of entering the loop again.
+    fn repeat(
+        &mut self,
+        from: Option<ElementStart>,
+        start: &Option<Arc<Mutex<SyntaxNode>>>,
+        last: &Arc<Mutex<SyntaxNode>>,
+        min: u32,
+        max: Option<u32>,
+    ) -> Option<Arc<Mutex<SyntaxNode>>> {
+        let (Some(from), Some(start)) = (from, start) else {
+            self.error_here(
+                "Nothing to repeat, a counted repetition has to follow an element or group",
+            );
+            return None;
+        };
+        let ptr = self.get_func_ptr();
+        let body = self.graph.force_get_node(ptr, NodeType::IDK);
+        let loop_id = self.get_func_ptr();
+        let loop_node = self.graph.force_get_node(loop_id, NodeType::REPEAT);
+        let ptr = self.get_func_ptr();
+        let exit = self.graph.force_get_node(ptr, NodeType::JUMP);
+
+        // The ways into the element turn into one way into the loop, weighted as they were
+        let start_id = {
+            let mut start = start.lock().unwrap();
+            let moved = start.options.split_off(from.edges);
+            let weight = moved.iter().map(|edge| edge.probability).sum();
+            body.lock().unwrap().options = moved;
+            start.add_edge(Arc::clone(&loop_node), weight);
+            start.id
+        };
+        let body_id = body.lock().unwrap().id;
+        for id in from.func_ptr + 1..body_id {
+            let Some(node) = self.graph.node_ref.get(&id) else {
+                continue;
+            };
+            for edge in &mut node.lock().unwrap().options {
+                if Arc::ptr_eq(&edge.node, start) {
+                    edge.node = Arc::clone(&body);
+                }
+            }
+        }
+        for lp in &mut self.loops {
+            if lp.target == start_id && lp.node > from.func_ptr {
+                lp.target = body_id;
             }
+        }
+        for ann in &mut self.annotations[from.annotations..] {
+            if ann.node == start_id {
+                ann.node = body_id;
+            }
+        }
+
+        last.lock().unwrap().add_edge(Arc::clone(&loop_node), 1.0);
+        // A drawn count leaves nothing for a weight to decide
+        let count = self.get_distribution();
+        let probability = match count {
+            Some(_) => 0.5,
+            None => self.get_probability(AnnotationKind::Repeat, &loop_node),
+        };
+        self.graph
+            .repeat_map
+            .insert(loop_id, Repeat { min, max, count });
+        {
+            let mut loop_node = loop_node.lock().unwrap();
+            loop_node.add_edge(body, probability);
+            loop_node.add_edge(Arc::clone(&exit), 1.0 - probability);
+        }
+        Some(exit)
+    }
+
+    fn distribution_follows(&self) -> bool {
+        self.tokens.get(self.index + 1).is_some_and(|token| {
+            token.typ == TokenType::Probability
+                && !is_regex_option(&token.text)
+                && Distribution::parse(&token.text).is_some()
+        })
+    }
+
+    // Reads the next `<regex>`, `<len=...>` or `<bias=...>` after a `[...]` as (its text, what
+    // it sets).
+    // One that doesn't parse is reported and skipped.
+    fn get_regex_option(&mut self) -> Option<(String, RegexOption)> {
+        loop {
+            let token = self.tokens.get(self.index + 1).filter(|token| {
+                token.typ == TokenType::Probability && is_regex_option(&token.text)
+            })?;
+            let annotation = token.text.trim().to_string();
             self.index += 1;
+
+            let option = if annotation == "regex" {
+                Ok(RegexOption::Syntax)
+            } else if let Some(length) = option_value(&annotation, "len") {
+                Distribution::parse_length(length).map(RegexOption::Length)
+            } else {
+                let name = option_value(&annotation, "bias").unwrap_or_default().trim();
+                self.bias_named(name).map(RegexOption::Bias)
+            };
+            match option {
+                Ok(option) => return Some((annotation, option)),
+                Err(msg) => self.error_here(msg),
+            }
+        }
+    }
+
+    // Tables from the config go first, so they can stand in for a built-in profile
+    fn bias_named(&self, name: &str) -> Result<Arc<dyn CharBias>, String> {
+        if let Some(table) = self.config.bias_tables.get(name) {
+            return Ok(Arc::clone(table));
+        }
+        match BiasProfile::from_name(name) {
+            Some(profile) => Ok(Arc::new(profile)),
+            None => Err(format!(
+                "Unknown bias '{}', expected uniform, english, hex, identifier or a table from the config",
+                name
+            )),
         }
+    }
 
-        (start_buffer, Some(buffer_node))
+    // Reads an optional length distribution after the current token, see `Distribution`
+    fn get_distribution(&mut self) -> Option<Distribution> {
+        if !self.distribution_follows() {
+            return None;
+        }
+        self.index += 1;
+        match Distribution::parse(&self.curr().text)? {
+            Ok(count) => Some(count),
+            Err(msg) => {
+                self.error_here(msg);
+                None
+            }
+        }
     }
 
-    fn get_probability(&mut self) -> f32 {
+    // Reads an optional `<p>` after the current token, noting it for the linter.
+    // `source` is the node whose outgoing edge the value ends up weighting.
+    fn get_probability(&mut self, kind: AnnotationKind, source: &Arc<Mutex<SyntaxNode>>) -> f32 {
         self.index += 1;
         if self.index < self.tokens.len() && self.tokens[self.index].typ == TokenType::Probability {
             let num = &self.tokens[self.index].text;
             match num.parse::<f32>() {
                 Ok(numf) => {
                     if numf < 0.0 {
-                        self.errors.push("Negative Probability Found".to_string());
+                        self.error_here("Negative Probability Found");
                         return 0.0;
                     }
+                    self.annotations.push(Annotation {
+                        rule: self.current_rule,
+                        span: self.curr_span(),
+                        value: numf,
+                        kind,
+                        node: source.lock().unwrap().id,
+                    });
                     return numf;
                 }
+                Err(_) if is_regex_option(num) => {
+                    self.error_here(
+                        "Regex syntax, string lengths and biases only go right after a '[...]'",
+                    );
+                    return 0.5;
+                }
+                Err(_) if Distribution::parse(num).is_some() => {
+                    self.error_here("Length distributions only go after '*', '+' or '{m,n}'");
+                    return 0.5;
+                }
                 Err(_) => {
+                    self.error_here("Failed to parse probability");
                     self.index -= 1;
-                    self.errors.push("Failed to parse probability".to_string());
                     return 0.0;
                 }
             }
@@ -324,3 +654,73 @@ impl Parser {
         0.5
     }
 }
+
+// What comes after `name=` in an annotation, None if it sets something else
+fn option_value<'a>(text: &'a str, name: &str) -> Option<&'a str> {
+    text.trim()
+        .strip_prefix(name)?
+        .trim_start()
+        .strip_prefix('=')
+}
+
+fn is_regex_option(text: &str) -> bool {
+    text.trim() == "regex"
+        || option_value(text, "len").is_some()
+        || option_value(text, "bias").is_some()
+}
+
+// Reads the inside of `{n}`, `{m,n}` or `{m,}` as (min, max)
+fn parse_bounds(text: &str) -> Option<(u32, Option<u32>)> {
+    let (min, max) = match text.split_once(',') {
+        None => {
+            let count = text.trim().parse().ok()?;
+            (count, Some(count))
+        }
+        Some((min, max)) => {
+            let max = max.trim();
+            let max = if max.is_empty() {
+                None
+            } else {
+                Some(max.parse().ok()?)
+            };
+            (min.trim().parse().ok()?, max)
+        }
+    };
+    Some((min, max))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Resrap, ResrapError};
+
+    #[test]
+    fn every_broken_statement_is_reported() {
+        let grammar = "a: 'x' b;\n: 'y';\nb: 'z' ) ;\nc 'w';\nd: 'ok';\n";
+        let mut resrap = Resrap::new();
+        let Err(ResrapError::Parse(diags)) =
+            resrap.parse_grammar("g".to_string(), grammar.to_string())
+        else {
+            panic!("expected parse errors");
+        };
+        let found: Vec<_> = diags
+            .iter()
+            .map(|d| {
+                let span = &d.span;
+                (
+                    d.message.as_str(),
+                    span.line,
+                    span.column,
+                    &grammar[span.start..span.end],
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("Expected Subject at start of statement", 2, 1, ":"),
+                ("Stray ')' found", 3, 8, ")"),
+                ("Expected Colon after Subject", 4, 3, "'w'"),
+            ]
+        );
+    }
+}
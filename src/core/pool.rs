@@ -0,0 +1,335 @@
+use std::{
+    any::Any,
+    collections::VecDeque,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex, mpsc::Sender},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::core::{error::ResrapError, frozen_graph::FrozenSyntaxGraph, prng::PRNG};
+
+/// One generation request waiting in the queue.
+pub struct Job {
+    pub id: String,
+    pub graph: Arc<FrozenSyntaxGraph>,
+    pub start: String,
+    pub seed: u64,
+    pub tokens: usize,
+}
+
+/// What a worker sends back for a job, tagged with the ID it was submitted under.
+#[derive(Debug)]
+pub struct JobResult {
+    pub id: String,
+    pub code: Result<Vec<String>, ResrapError>,
+}
+
+/// How many workers a pool keeps and how long it holds on to idle ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolConfig {
+    /// Workers kept alive even when there is nothing to do, can be 0.
+    pub min_workers: usize,
+    /// Most workers running at once. More are started while jobs wait and no worker is free.
+    pub max_workers: usize,
+    /// Jobs that can wait in the queue before submitting blocks.
+    pub queue_size: usize,
+    /// How long a worker above `min_workers` waits for a job before it exits.
+    pub idle_timeout: Duration,
+}
+
+impl PoolConfig {
+    /// A pool that always runs exactly `workers` threads.
+    pub fn fixed(workers: usize, queue_size: usize) -> Self {
+        PoolConfig {
+            min_workers: workers,
+            max_workers: workers,
+            queue_size,
+            idle_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Default for PoolConfig {
+    /// Scales between 1 worker and one per core, dropping idle ones after 30 seconds.
+    fn default() -> Self {
+        PoolConfig {
+            min_workers: 1,
+            max_workers: thread::available_parallelism().map_or(4, |n| n.get()),
+            queue_size: 1000,
+            idle_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A snapshot of what a pool is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PoolStats {
+    /// Jobs waiting for a worker.
+    pub queue_depth: usize,
+    /// Worker threads alive, busy or not.
+    pub workers: usize,
+    /// Workers busy with a job right now.
+    pub active: usize,
+    /// Jobs finished since the pool started.
+    pub jobs_done: u64,
+}
+
+struct Queue {
+    jobs: VecDeque<Job>,
+    closed: bool,
+    workers: usize,
+    active: usize,
+    jobs_done: u64,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    config: PoolConfig,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+/// Worker threads pulling jobs from a bounded queue, between `min_workers` and `max_workers`
+/// of them depending on load.
+/// Grammars travel with the job as `Arc`s, so workers never lock anything while walking.
+pub struct WorkerPool {
+    shared: Arc<Shared>,
+    handles: Mutex<Vec<JoinHandle<()>>>,
+    results: Sender<JobResult>,
+}
+
+impl WorkerPool {
+    pub fn new(mut config: PoolConfig, results: Sender<JobResult>) -> Self {
+        config.max_workers = config.max_workers.max(config.min_workers).max(1);
+        config.queue_size = config.queue_size.max(1);
+        let min_workers = config.min_workers;
+
+        let pool = WorkerPool {
+            shared: Arc::new(Shared {
+                queue: Mutex::new(Queue {
+                    jobs: VecDeque::new(),
+                    closed: false,
+                    workers: 0,
+                    active: 0,
+                    jobs_done: 0,
+                }),
+                config,
+                not_empty: Condvar::new(),
+                not_full: Condvar::new(),
+            }),
+            handles: Mutex::new(vec![]),
+            results,
+        };
+        {
+            let mut queue = pool.shared.queue.lock().unwrap();
+            for _ in 0..min_workers {
+                pool.spawn(&mut queue);
+            }
+        }
+        pool
+    }
+
+    /// Queues a job, blocking while the queue is full.
+    /// Starts another worker if the job would otherwise have to wait for a busy one.
+    pub fn submit(&self, job: Job) -> Result<(), ResrapError> {
+        let config = &self.shared.config;
+        let mut queue = self.shared.queue.lock().unwrap();
+        while queue.jobs.len() >= config.queue_size && !queue.closed {
+            queue = self.shared.not_full.wait(queue).unwrap();
+        }
+        if queue.closed {
+            return Err(ResrapError::PoolNotRunning);
+        }
+        queue.jobs.push_back(job);
+
+        let idle = queue.workers - queue.active;
+        if queue.jobs.len() > idle && queue.workers < config.max_workers {
+            self.spawn(&mut queue);
+        }
+        self.shared.not_empty.notify_one();
+        Ok(())
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let queue = self.shared.queue.lock().unwrap();
+        PoolStats {
+            queue_depth: queue.jobs.len(),
+            workers: queue.workers,
+            active: queue.active,
+            jobs_done: queue.jobs_done,
+        }
+    }
+
+    /// Stops taking jobs, lets the workers finish everything already queued and joins them.
+    pub fn shutdown(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.not_empty.notify_all();
+        self.shared.not_full.notify_all();
+        for handle in self.handles.lock().unwrap().drain(..) {
+            let _ = handle.join();
+        }
+    }
+
+    // Takes the queue guard so the worker count can't race with workers exiting
+    fn spawn(&self, queue: &mut Queue) {
+        queue.workers += 1;
+        let shared = Arc::clone(&self.shared);
+        let results = self.results.clone();
+        let mut handles = self.handles.lock().unwrap();
+        // Workers that timed out are gone already, joining them is instant
+        handles.retain(|handle| !handle.is_finished());
+        handles.push(thread::spawn(move || work(&shared, &results)));
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn work(shared: &Shared, results: &Sender<JobResult>) {
+    loop {
+        let job = {
+            let mut queue = shared.queue.lock().unwrap();
+            let deadline = Instant::now() + shared.config.idle_timeout;
+            loop {
+                if let Some(job) = queue.jobs.pop_front() {
+                    queue.active += 1;
+                    shared.not_full.notify_one();
+                    break job;
+                }
+                if queue.closed {
+                    queue.workers -= 1;
+                    return;
+                }
+                if queue.workers > shared.config.min_workers {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        queue.workers -= 1;
+                        return;
+                    }
+                    queue = shared
+                        .not_empty
+                        .wait_timeout(queue, deadline - now)
+                        .unwrap()
+                        .0;
+                } else {
+                    queue = shared.not_empty.wait(queue).unwrap();
+                }
+            }
+        };
+
+        // A walk that panics fails its job like any other error, and the counts stay right
+        let Job {
+            id,
+            graph,
+            start,
+            seed,
+            tokens,
+        } = job;
+        let code = panic::catch_unwind(AssertUnwindSafe(move || {
+            graph.walk_graph(PRNG::new(seed), start, tokens)
+        }))
+        .unwrap_or_else(|payload| Err(ResrapError::GenerationPanicked(panic_message(&*payload))));
+        {
+            let mut queue = shared.queue.lock().unwrap();
+            queue.active -= 1;
+            queue.jobs_done += 1;
+        }
+        // Nobody listening any more is not the worker's problem
+        let _ = results.send(JobResult { id, code });
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, mpsc};
+
+    use super::*;
+    use crate::{Resrap, ResrapMT};
+
+    fn started() -> ResrapMT {
+        let mut resrap = ResrapMT::new(2, 4);
+        resrap
+            .parse_grammar("g".to_string(), "s: 'a' 'b'^;".to_string())
+            .unwrap();
+        resrap.start();
+        resrap
+    }
+
+    #[test]
+    fn code_channel_ends_after_shutdown() {
+        let mut resrap = started();
+        let results = resrap.take_code_channel().unwrap();
+        for i in 0..10 {
+            resrap
+                .generate_with_seed(i.to_string(), "g", "s".to_string(), i, 5)
+                .unwrap();
+        }
+        resrap.shutdown();
+        assert_eq!(results.iter().count(), 10);
+
+        resrap.start();
+        let results = resrap.take_code_channel().unwrap();
+        resrap
+            .generate_with_seed("again".to_string(), "g", "s".to_string(), 1, 5)
+            .unwrap();
+        resrap.shutdown();
+        assert_eq!(
+            results.iter().map(|result| result.id).collect::<Vec<_>>(),
+            ["again"]
+        );
+    }
+
+    #[test]
+    fn panicking_job_fails_and_frees_its_worker() {
+        let mut resrap = Resrap::new();
+        resrap
+            .parse_grammar("g".to_string(), "s: 'a';".to_string())
+            .unwrap();
+        let bytes = resrap.compile_grammar("g").unwrap();
+        let graph = Arc::new(FrozenSyntaxGraph::from_bytes(&bytes).unwrap());
+        let mut broken = FrozenSyntaxGraph::from_bytes(&bytes).unwrap();
+        // A start rule whose node index is past the end makes the walk panic
+        let start = broken.name_map["s"];
+        broken.index.insert(start, u32::MAX);
+        let broken = Arc::new(broken);
+
+        let (tx, rx) = mpsc::channel();
+        let mut pool = WorkerPool::new(PoolConfig::fixed(1, 4), tx);
+        for (id, graph) in [("broken", &broken), ("fine", &graph)] {
+            pool.submit(Job {
+                id: id.to_string(),
+                graph: Arc::clone(graph),
+                start: "s".to_string(),
+                seed: 1,
+                tokens: 3,
+            })
+            .unwrap();
+        }
+        let first = rx.recv().unwrap();
+        assert_eq!(first.id, "broken");
+        assert!(matches!(
+            first.code,
+            Err(ResrapError::GenerationPanicked(_))
+        ));
+        let second = rx.recv().unwrap();
+        assert_eq!(second.code.unwrap(), ["a"]);
+
+        pool.shutdown();
+        let stats = pool.stats();
+        assert_eq!((stats.workers, stats.active, stats.jobs_done), (0, 0, 2));
+    }
+}
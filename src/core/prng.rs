@@ -1,3 +1,5 @@
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone)]
 pub struct PRNG {
     seed: u64,
     number: u64,
@@ -14,11 +16,19 @@ impl PRNG {
         self.seed = seed;
     }
 
+    // (seed, number), enough to continue the exact same sequence with `from_state`
+    pub(crate) fn state(&self) -> (u64, u64) {
+        (self.seed, self.number)
+    }
+    pub(crate) fn from_state(seed: u64, number: u64) -> Self {
+        PRNG { seed, number }
+    }
+
     pub fn next_prn(&mut self) -> u64 {
         self.number ^= self.number << 13;
         self.number ^= self.number >> 7;
         self.number ^= self.number << 17;
-        return self.number;
+        self.number
     }
     pub fn random(&mut self) -> f64 {
         let var = (self.next_prn() >> 11) as f64;
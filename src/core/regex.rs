@@ -1,38 +1,209 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::OnceLock};
 
-use crate::core::prng::PRNG;
+use crate::core::{
+    bias::CharBias,
+    codec::{Reader, Writer},
+    distribution::Distribution,
+    prng::PRNG,
+    regex_parser::{self, Ast},
+};
 
+// How likely each further round of `*`, `+` and `{m,}` is, three more on average
+const MORE: f32 = 0.75;
+// Splits one string may take its first way at, so loops end even when the PRNG keeps
+// drawing 0. Past it every split takes its second way, which never loops back.
+const MAX_SPLITS: u32 = 10_000;
+// Longest string a `<len=...>` asks for, longer draws are cut down to it
+const MAX_LENGTH: u32 = 1000;
+// Most instructions one regex compiles to, its closing `Match` included. Counts copy their
+// body, so nested ones multiply. Loading a compiled grammar holds programs to the same limit.
+pub(crate) const MAX_PROGRAM: usize = 4096;
+
+/// A weighted set of chars, one step of a regex picks one of them.
 #[derive(Debug, Clone)]
 pub struct CacheRexState {
-    cumu_freq: Vec<f32>,
-    options: Vec<char>,
+    pub cumu_freq: Vec<f32>,
+    pub options: Vec<char>,
+}
+
+/// One instruction of a compiled regex. A program runs from its first instruction until it
+/// reaches `Match`, every step going to the next instruction unless it says otherwise.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Inst {
+    /// Prints one char of the class at this index.
+    Pick(u32),
+    /// Prints a string of 3 to 7 chars of the class, what a plain `[a-z]` has always done.
+    /// With a length it prints as many as the rest of the program leaves.
+    Run(u32),
+    /// Goes to `first` with probability `weight`, to `second` otherwise. `second` never
+    /// leads back to an earlier instruction.
+    Split {
+        first: u32,
+        second: u32,
+        weight: f32,
+    },
+    Jump(u32),
+    Match,
+}
+
+// Fewest steps from each instruction to `Match` by the number of chars still to print, see `plan`
+type Plan = Vec<Vec<Option<u32>>>;
+
+/// A compiled regex.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    pub program: Vec<Inst>,
+    /// How long its strings are, None to leave it to the program.
+    pub length: Option<Distribution>,
+    // Worked out on the first sized string and kept for the rest
+    plan: OnceLock<Plan>,
+}
+
+impl Pattern {
+    fn new(program: Vec<Inst>, length: Option<Distribution>) -> Self {
+        Pattern {
+            program,
+            length,
+            plan: OnceLock::new(),
+        }
+    }
+
+    // The plan for every length up to MAX_LENGTH. A string longer than `len + program.len()`
+    // has a loop in it that can be left out without going under `len`, so the closest length
+    // to any draw is always in it.
+    fn plan(&self) -> &Plan {
+        self.plan
+            .get_or_init(|| plan(&self.program, MAX_LENGTH as usize + self.program.len()))
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Regexer {
-    pub cached_rex: HashMap<String, CacheRexState>,
+    /// Regex text, with the `<regex>`, `<len=...>` and `<bias=...>` after it, to its index in
+    /// `patterns`.
+    pub cached_rex: HashMap<String, u32>,
+    /// Char classes the programs pick from.
+    pub classes: Vec<CacheRexState>,
+    pub patterns: Vec<Pattern>,
 }
 
 impl Regexer {
     pub fn new() -> Self {
         Regexer {
             cached_rex: HashMap::new(),
+            classes: vec![],
+            patterns: vec![],
+        }
+    }
+
+    pub fn pattern_index(&self, regex: &str) -> Option<u32> {
+        self.cached_rex.get(regex).copied()
+    }
+
+    /// Generates a string from the program at `pattern`, resolved beforehand with `pattern_index`.
+    pub fn generate(&self, pattern: u32, prn: &mut PRNG) -> String {
+        let mut result = String::new();
+        let Some(pattern) = self.patterns.get(pattern as usize) else {
+            return result;
+        };
+        let program = &pattern.program;
+        if let Some(length) = &pattern.length {
+            let len = length.sample(prn).min(MAX_LENGTH);
+            if let Some(result) = self.generate_sized(pattern, len as usize, prn) {
+                return result;
+            }
+        }
+
+        let mut pc = 0;
+        let mut splits = 0;
+        loop {
+            match program[pc] {
+                Inst::Pick(class) => {
+                    result.push(self.pick(class, prn));
+                    pc += 1;
+                }
+                Inst::Run(class) => {
+                    let size = prn.random_int(3, 4); // generate size between 3 and 4 (you can adjust for 3-7)
+                    for _ in 0..size {
+                        result.push(self.pick(class, prn));
+                    }
+                    pc += 1;
+                }
+                Inst::Split {
+                    first,
+                    second,
+                    weight,
+                } => {
+                    splits += 1;
+                    pc = if splits <= MAX_SPLITS && prn.random() as f32 <= weight {
+                        first
+                    } else {
+                        second
+                    } as usize;
+                }
+                Inst::Jump(target) => pc = target as usize,
+                Inst::Match => return result,
+            }
         }
     }
-    pub fn generate_string(&self, regex: &str, prn: &mut PRNG) -> String {
-        let size = prn.random_int(3, 4); // generate size between 3 and 4 (you can adjust for 3-7)
-        let mut result = String::with_capacity(size as usize);
 
-        if let Some(state) = self.cached_rex.get(regex) {
-            for _ in 0..size {
-                let x = prn.random(); // float 0-1
+    // Runs `program` so it prints `len` chars, or as close to that as it can. Every split
+    // only goes where the rest of the program can still come out at that length.
+    fn generate_sized(&self, pattern: &Pattern, len: usize, prn: &mut PRNG) -> Option<String> {
+        let program = &pattern.program;
+        let steps = pattern.plan();
+        let mut left = (0..steps.len())
+            .filter(|&len| steps[len][0].is_some())
+            .min_by_key(|&other| (other.abs_diff(len), other))?;
 
-                let idx = closest_index(&state.cumu_freq, x as f32);
-                result.push(state.options[idx]);
+        let mut result = String::new();
+        let mut pc = 0;
+        let mut splits = 0;
+        loop {
+            match program[pc] {
+                Inst::Pick(class) => {
+                    result.push(self.pick(class, prn));
+                    left -= 1;
+                    pc += 1;
+                }
+                Inst::Run(class) => {
+                    for _ in 0..left {
+                        result.push(self.pick(class, prn));
+                    }
+                    left = 0;
+                    pc += 1;
+                }
+                Inst::Split {
+                    first,
+                    second,
+                    weight,
+                } => {
+                    splits += 1;
+                    // Past MAX_SPLITS the shorter way, which always gets closer to the end
+                    pc = match (steps[left][first as usize], steps[left][second as usize]) {
+                        (Some(_), Some(_)) if splits <= MAX_SPLITS => {
+                            if prn.random() as f32 <= weight {
+                                first
+                            } else {
+                                second
+                            }
+                        }
+                        (Some(a), Some(b)) if a <= b => first,
+                        (Some(_), None) => first,
+                        _ => second,
+                    } as usize;
+                }
+                Inst::Jump(target) => pc = target as usize,
+                Inst::Match => return Some(result),
             }
         }
+    }
 
-        result
+    fn pick(&self, class: u32, prn: &mut PRNG) -> char {
+        let state = &self.classes[class as usize];
+        let x = prn.random(); // float 0-1
+        let idx = closest_index(&state.cumu_freq, x as f32);
+        state.options[idx]
     }
 
     fn expand_class(&self, class: &str) -> Vec<char> {
@@ -55,15 +226,146 @@ impl Regexer {
         chars
     }
 
-    pub fn cache_regex(&mut self, regex: &str) {
-        let tokens = self.expand_class(regex);
+    /// Compiles `regex` to print strings of `length` with chars weighed by `bias`, unless `key`
+    /// already was. Text that `has_syntax`, or any with `syntax` for a `<regex>`, is a regex.
+    /// The rest is read the way `[...]` always has been, as a class of chars and ranges
+    /// printing a short run of them.
+    pub fn cache_regex(
+        &mut self,
+        key: &str,
+        regex: &str,
+        syntax: bool,
+        length: Option<Distribution>,
+        bias: &dyn CharBias,
+    ) -> Result<(), String> {
+        if self.cached_rex.contains_key(key) {
+            return Ok(());
+        }
+        let program = if syntax || regex_parser::has_syntax(regex) {
+            let ast = regex_parser::parse(regex)?;
+            let mut program = vec![];
+            self.compile(&ast, &mut program, bias)?;
+            program.push(Inst::Match);
+            if program.len() > MAX_PROGRAM {
+                return Err(too_long());
+            }
+            program
+        } else {
+            let chars = self.expand_class(regex);
+            if chars.is_empty() {
+                return Err("empty class".to_string());
+            }
+            let class = self.add_class(chars, bias);
+            vec![Inst::Run(class), Inst::Match]
+        };
+        self.cached_rex
+            .insert(key.to_string(), self.patterns.len() as u32);
+        self.patterns.push(Pattern::new(program, length));
+        Ok(())
+    }
+
+    fn compile(
+        &mut self,
+        ast: &Ast,
+        program: &mut Vec<Inst>,
+        bias: &dyn CharBias,
+    ) -> Result<(), String> {
+        // Stops early, the whole program is checked once it is done
+        if program.len() >= MAX_PROGRAM {
+            return Err(too_long());
+        }
+        let here = |program: &Vec<Inst>| program.len() as u32;
+        match ast {
+            Ast::Empty => {}
+            Ast::Set(chars) => {
+                let class = self.add_class(chars.clone(), bias);
+                program.push(Inst::Pick(class));
+            }
+            Ast::Concat(items) => {
+                for item in items {
+                    self.compile(item, program, bias)?;
+                }
+            }
+            Ast::Alt(branches) => {
+                // Every branch equally likely: each split takes its branch or leaves it to the rest
+                let mut jumps = vec![];
+                for (i, branch) in branches.iter().enumerate() {
+                    let rest = branches.len() - i;
+                    if rest == 1 {
+                        self.compile(branch, program, bias)?;
+                        break;
+                    }
+                    let split = here(program);
+                    program.push(Inst::Match); // patched once the branch is in
+                    self.compile(branch, program, bias)?;
+                    jumps.push(here(program));
+                    program.push(Inst::Match);
+                    program[split as usize] = Inst::Split {
+                        first: split + 1,
+                        second: here(program),
+                        weight: 1.0 / rest as f32,
+                    };
+                }
+                let end = here(program);
+                for jump in jumps {
+                    program[jump as usize] = Inst::Jump(end);
+                }
+            }
+            Ast::Repeat { inner, min, max } => {
+                for _ in 0..*min {
+                    self.compile(inner, program, bias)?;
+                }
+                match max {
+                    // Each optional round goes on with the odds that keep every count equally
+                    // likely, all of them skip to the end once they stop
+                    Some(max) => {
+                        let rounds = max - min;
+                        let mut splits = vec![];
+                        for _ in 0..rounds {
+                            splits.push(here(program));
+                            program.push(Inst::Match); // patched once the end is known
+                            self.compile(inner, program, bias)?;
+                        }
+                        let end = here(program);
+                        for (i, split) in splits.into_iter().enumerate() {
+                            let left = (rounds as usize - i) as f32;
+                            program[split as usize] = Inst::Split {
+                                first: split + 1,
+                                second: end,
+                                weight: left / (left + 1.0),
+                            };
+                        }
+                    }
+                    None => {
+                        let split = here(program);
+                        program.push(Inst::Match); // patched once the end is known
+                        self.compile(inner, program, bias)?;
+                        program.push(Inst::Jump(split));
+                        program[split as usize] = Inst::Split {
+                            first: split + 1,
+                            second: here(program),
+                            weight: MORE,
+                        };
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Adds a class weighted by `bias` and returns its index, reusing an equal one
+    fn add_class(&mut self, tokens: Vec<char>, bias: &dyn CharBias) -> u32 {
         let mut bias_arr: Vec<f32> = Vec::with_capacity(tokens.len());
         let mut sum: f32 = 0.0;
         for token in &tokens {
-            let bias = self.bias(token.clone()) as f32;
+            let bias = bias.weight(*token).max(0.0);
             bias_arr.push(bias);
             sum += bias;
         }
+        if sum <= 0.0 || !sum.is_finite() {
+            bias_arr.fill(1.0);
+            sum = tokens.len() as f32;
+        }
         for b in &mut bias_arr {
             *b /= sum;
         }
@@ -73,36 +375,247 @@ impl Regexer {
             cum += w;
             cdf.push(cum);
         }
-        self.cached_rex.insert(
-            regex.to_string(),
-            CacheRexState {
-                cumu_freq: cdf,
-                options: tokens,
+        if let Some(i) = self
+            .classes
+            .iter()
+            .position(|c| c.options == tokens && c.cumu_freq == cdf)
+        {
+            return i as u32;
+        }
+        self.classes.push(CacheRexState {
+            cumu_freq: cdf,
+            options: tokens,
+        });
+        self.classes.len() as u32 - 1
+    }
+
+    pub(crate) fn encode(&self, out: &mut Writer) {
+        out.u32(self.classes.len() as u32);
+        for class in &self.classes {
+            encode_class(class, out);
+        }
+        out.u32(self.patterns.len() as u32);
+        for pattern in &self.patterns {
+            out.u32(pattern.program.len() as u32);
+            for inst in &pattern.program {
+                inst.encode(out);
+            }
+            encode_length(&pattern.length, out);
+        }
+        let mut cached: Vec<_> = self.cached_rex.iter().collect();
+        cached.sort();
+        out.u32(cached.len() as u32);
+        for (text, &pattern) in cached {
+            out.str(text);
+            out.u32(pattern);
+        }
+    }
+
+    // Writes the program at `pattern` with every class it picks from spelled out, for hashing
+    pub(crate) fn encode_pattern(&self, pattern: u32, out: &mut Writer) {
+        let Some(pattern) = self.patterns.get(pattern as usize) else {
+            return;
+        };
+        for inst in &pattern.program {
+            inst.encode(out);
+            if let Inst::Pick(class) | Inst::Run(class) = inst {
+                encode_class(&self.classes[*class as usize], out);
+            }
+        }
+        encode_length(&pattern.length, out);
+    }
+
+    /// Reads what `encode` wrote, checking that programs stay inside themselves and the classes.
+    pub(crate) fn decode(input: &mut Reader) -> Result<Self, String> {
+        let mut regexer = Regexer::new();
+        let count = input.u32()?;
+        for _ in 0..count {
+            let len = input.u32()?;
+            if len == 0 {
+                return Err("empty regex class".to_string());
+            }
+            let mut class = CacheRexState {
+                cumu_freq: vec![],
+                options: vec![],
+            };
+            for _ in 0..len {
+                let ch = input.u32()?;
+                class
+                    .options
+                    .push(char::from_u32(ch).ok_or(format!("invalid char {:#x}", ch))?);
+                class.cumu_freq.push(input.f32()?);
+            }
+            regexer.classes.push(class);
+        }
+
+        let count = input.u32()?;
+        for _ in 0..count {
+            let len = input.u32()?;
+            if len as usize > MAX_PROGRAM {
+                return Err(format!("regex program of {} instructions", len));
+            }
+            let mut program = vec![];
+            for _ in 0..len {
+                program.push(Inst::decode(input)?);
+            }
+            let classes = regexer.classes.len() as u32;
+            for (pc, inst) in program.iter().enumerate() {
+                // Past MAX_SPLITS a program has to run forward to its end, so going back is only
+                // allowed to a split that then jumps past where it came from
+                let ok = match *inst {
+                    Inst::Pick(class) | Inst::Run(class) => {
+                        class < classes && pc + 1 < program.len()
+                    }
+                    Inst::Split { first, second, .. } => {
+                        (first as usize) < program.len()
+                            && (second as usize) < program.len()
+                            && second as usize > pc
+                    }
+                    Inst::Jump(target) => match program.get(target as usize) {
+                        Some(_) if target as usize > pc => true,
+                        Some(Inst::Split { second, .. }) => *second as usize > pc,
+                        _ => false,
+                    },
+                    Inst::Match => true,
+                };
+                if !ok {
+                    return Err(format!("regex instruction {} out of range", pc));
+                }
+            }
+            if program.is_empty() {
+                return Err("empty regex program".to_string());
+            }
+            let length = match input.u8()? {
+                0 => None,
+                1 => Some(Distribution::decode(input)?),
+                flag => return Err(format!("bad regex length flag {}", flag)),
+            };
+            regexer.patterns.push(Pattern::new(program, length));
+        }
+
+        let count = input.u32()?;
+        for _ in 0..count {
+            let text = input.str()?;
+            let pattern = input.u32()?;
+            if pattern as usize >= regexer.patterns.len() {
+                return Err(format!("regex {} out of range", pattern));
+            }
+            regexer.cached_rex.insert(text, pattern);
+        }
+        Ok(regexer)
+    }
+}
+
+impl Inst {
+    fn encode(&self, out: &mut Writer) {
+        match *self {
+            Inst::Pick(class) => {
+                out.u8(0);
+                out.u32(class);
+            }
+            Inst::Run(class) => {
+                out.u8(1);
+                out.u32(class);
+            }
+            Inst::Split {
+                first,
+                second,
+                weight,
+            } => {
+                out.u8(2);
+                out.u32(first);
+                out.u32(second);
+                out.f32(weight);
+            }
+            Inst::Jump(target) => {
+                out.u8(3);
+                out.u32(target);
+            }
+            Inst::Match => out.u8(4),
+        }
+    }
+
+    fn decode(input: &mut Reader) -> Result<Self, String> {
+        Ok(match input.u8()? {
+            0 => Inst::Pick(input.u32()?),
+            1 => Inst::Run(input.u32()?),
+            2 => Inst::Split {
+                first: input.u32()?,
+                second: input.u32()?,
+                weight: input.f32()?,
             },
-        );
-    }
-    fn bias(&self, r: char) -> i32 {
-        let r_lower = r.to_ascii_lowercase();
-
-        match r_lower {
-            'e' => 12,
-            'a' | 'i' | 'o' => 9,
-            'n' | 'r' | 't' | 's' | 'l' => 6,
-            'c' | 'd' | 'm' | 'u' | 'p' | 'b' | 'g' => 4,
-            'f' | 'h' | 'v' | 'k' | 'w' | 'y' => 3,
-            'j' | 'x' | 'q' | 'z' => 1,
-            _ => {
-                if r.is_uppercase() {
-                    self.bias(r.to_ascii_lowercase()) / 2
-                } else if r.is_digit(10) {
-                    3
-                } else if r == '_' {
-                    5
-                } else {
-                    1
+            3 => Inst::Jump(input.u32()?),
+            4 => Inst::Match,
+            tag => return Err(format!("unknown regex instruction {}", tag)),
+        })
+    }
+}
+
+fn too_long() -> String {
+    format!(
+        "it compiles to more than {} instructions once its counts are multiplied out",
+        MAX_PROGRAM
+    )
+}
+
+fn encode_length(length: &Option<Distribution>, out: &mut Writer) {
+    match length {
+        Some(length) => {
+            out.u8(1);
+            length.encode(out);
+        }
+        None => out.u8(0),
+    }
+}
+
+// Fewest steps from each instruction to `Match` printing exactly `len` more chars, for every
+// `len` up to `max_len`. None where that can't be done.
+fn plan(program: &[Inst], max_len: usize) -> Vec<Vec<Option<u32>>> {
+    let mut steps: Vec<Vec<Option<u32>>> = Vec::with_capacity(max_len + 1);
+    for len in 0..=max_len {
+        let mut row: Vec<Option<u32>> = vec![None; program.len()];
+        // Splits and jumps stay on this row and can go in circles, so settle it
+        loop {
+            let mut changed = false;
+            for pc in (0..program.len()).rev() {
+                let next = match program[pc] {
+                    Inst::Pick(_) if len == 0 => None,
+                    Inst::Pick(_) => steps[len - 1][pc + 1],
+                    Inst::Run(_) if len == 0 => row[pc + 1],
+                    Inst::Run(_) => steps[0][pc + 1],
+                    Inst::Split { first, second, .. } => {
+                        match (row[first as usize], row[second as usize]) {
+                            (Some(a), Some(b)) => Some(a.min(b)),
+                            (a, b) => a.or(b),
+                        }
+                    }
+                    Inst::Jump(target) => row[target as usize],
+                    Inst::Match if len == 0 => Some(0),
+                    Inst::Match => None,
+                };
+                let candidate = match program[pc] {
+                    Inst::Match => next,
+                    _ => next.map(|steps| steps + 1),
+                };
+                if candidate.is_some_and(|new| row[pc].is_none_or(|old| new < old)) {
+                    row[pc] = candidate;
+                    changed = true;
                 }
             }
+            if !changed {
+                break;
+            }
         }
+        steps.push(row);
+    }
+    steps
+}
+
+fn encode_class(class: &CacheRexState, out: &mut Writer) {
+    out.u32(class.options.len() as u32);
+    for (&ch, &freq) in class.options.iter().zip(&class.cumu_freq) {
+        out.u32(ch as u32);
+        out.f32(freq);
     }
 }
 
@@ -114,3 +627,64 @@ fn closest_index(cdf: &[f32], x: f32) -> usize {
     }
     cdf.len() - 1
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::Resrap;
+
+    fn strings(grammar: &str, seeds: u64) -> Vec<String> {
+        let mut resrap = Resrap::new();
+        resrap
+            .parse_grammar("g".to_string(), grammar.to_string())
+            .unwrap();
+        (0..seeds)
+            .map(|seed| {
+                let seed = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+                resrap
+                    .generate_with_seed("g", "s".to_string(), seed, 1)
+                    .unwrap()
+                    .concat()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn plain_class_keeps_its_meaning() {
+        for out in strings("s: [a-z.];", 50) {
+            assert!((3..=6).contains(&out.len()), "{:?}", out);
+            assert!(out.chars().all(|c| c.is_ascii_lowercase() || c == '.'));
+        }
+        // `+-*` is an empty range, as it always was, which leaves the `/`
+        for out in strings("s: [+-*/];", 50) {
+            assert!((3..=6).contains(&out.len()), "{:?}", out);
+            assert!(out.chars().all(|c| c == '/'));
+        }
+    }
+
+    #[test]
+    fn nested_counts_are_capped() {
+        let err = Resrap::new()
+            .parse_grammar(
+                "g".to_string(),
+                "s: [((a{1000}){1000}){10}]<regex>;".to_string(),
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("Invalid regex"), "{}", err);
+    }
+
+    #[test]
+    fn nested_class_or_count_makes_a_regex() {
+        for out in strings("s: [0x[0-9a-f]{2,4}];", 50) {
+            let digits = out.strip_prefix("0x").unwrap();
+            assert!((2..=4).contains(&digits.len()), "{:?}", out);
+            assert!(digits.chars().all(|c| c.is_ascii_hexdigit()));
+        }
+        for out in strings("s: [(cat|dog)s?]<regex>;", 20) {
+            assert!(
+                ["cat", "cats", "dog", "dogs"].contains(&out.as_str()),
+                "{:?}",
+                out
+            );
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::BTreeSet;
+
+// Largest count `{m,n}` takes, its body is copied that many times
+const MAX_REPEAT: u32 = 1000;
+
+/// A parsed regex, see `parse`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ast {
+    /// Matches without printing anything, like an anchor.
+    Empty,
+    /// One char out of these, sorted.
+    Set(Vec<char>),
+    Concat(Vec<Ast>),
+    Alt(Vec<Ast>),
+    Repeat {
+        inner: Box<Ast>,
+        min: u32,
+        max: Option<u32>,
+    },
+}
+
+/// Whether `[...]` text is a regex without being marked `<regex>`: it has a class of its
+/// own, like `[0x[0-9a-f]{2,8}]`, or a `{m,n}` count. Anything else keeps meaning the list of
+/// chars and ranges `[...]` always has, so `[a-z.]` and `[+-*/]` stay plain classes.
+pub fn has_syntax(text: &str) -> bool {
+    text.contains('[')
+        || text.match_indices('{').any(|(i, _)| {
+            text[i + 1..].split_once('}').is_some_and(|(count, _)| {
+                count.starts_with(|c: char| c.is_ascii_digit())
+                    && count.chars().all(|c| c.is_ascii_digit() || c == ',')
+            })
+        })
+}
+
+/// Parses the regex subset `[...]` accepts: classes with ranges, negation and escapes, `.`,
+/// `\d \w \s` and their negations, `* + ? {n} {m,n} {m,}`, `|`, groups and the anchors
+/// `^ $ \b`.
+pub fn parse(text: &str) -> Result<Ast, String> {
+    let mut parser = RegexParser {
+        chars: text.chars().collect(),
+        pos: 0,
+    };
+    let ast = parser.alternation()?;
+    match parser.peek() {
+        None => Ok(ast),
+        Some(c) => Err(format!("unexpected '{}' at {}", c, parser.pos)),
+    }
+}
+
+// Printable ASCII, what `.` and negated classes pick from
+fn universe() -> impl Iterator<Item = char> {
+    ' '..='~'
+}
+
+fn perl_class(c: char) -> Option<BTreeSet<char>> {
+    let set: BTreeSet<char> = match c.to_ascii_lowercase() {
+        'd' => ('0'..='9').collect(),
+        'w' => ('a'..='z')
+            .chain('A'..='Z')
+            .chain('0'..='9')
+            .chain(['_'])
+            .collect(),
+        's' => [' ', '\t', '\n'].into_iter().collect(),
+        _ => return None,
+    };
+    if c.is_ascii_uppercase() {
+        Some(universe().filter(|c| !set.contains(c)).collect())
+    } else {
+        Some(set)
+    }
+}
+
+fn escaped(c: char) -> char {
+    match c {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        _ => c,
+    }
+}
+
+struct RegexParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl RegexParser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn alternation(&mut self) -> Result<Ast, String> {
+        let mut branches = vec![self.concat()?];
+        while self.eat('|') {
+            branches.push(self.concat()?);
+        }
+        Ok(if branches.len() == 1 {
+            branches.remove(0)
+        } else {
+            Ast::Alt(branches)
+        })
+    }
+
+    fn concat(&mut self) -> Result<Ast, String> {
+        let mut items = vec![];
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            items.push(self.repeat()?);
+        }
+        Ok(if items.len() == 1 {
+            items.remove(0)
+        } else {
+            Ast::Concat(items)
+        })
+    }
+
+    fn repeat(&mut self) -> Result<Ast, String> {
+        let mut ast = self.atom()?;
+        loop {
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => {
+                    self.pos += 1;
+                    self.bounds()?
+                }
+                _ => return Ok(ast),
+            };
+            self.pos += 1;
+            ast = Ast::Repeat {
+                inner: Box::new(ast),
+                min,
+                max,
+            };
+        }
+    }
+
+    // The inside of `{...}`, leaves `pos` on the closing brace
+    fn bounds(&mut self) -> Result<(u32, Option<u32>), String> {
+        let start = self.pos;
+        let Some(len) = self.chars[start..].iter().position(|&c| c == '}') else {
+            return Err("unclosed '{'".to_string());
+        };
+        let text: String = self.chars[start..start + len].iter().collect();
+        self.pos = start + len;
+
+        let count = |text: &str| text.trim().parse::<u32>().ok();
+        let bounds = match text.split_once(',') {
+            None => count(&text).map(|n| (n, Some(n))),
+            Some((min, max)) if max.trim().is_empty() => count(min).map(|min| (min, None)),
+            Some((min, max)) => count(min)
+                .zip(count(max))
+                .map(|(min, max)| (min, Some(max))),
+        };
+        match bounds {
+            None => Err(format!(
+                "expected {{n}}, {{m,n}} or {{m,}}, got {{{}}}",
+                text
+            )),
+            Some((min, Some(max))) if max < min => Err(format!("{{{}}} is empty", text)),
+            Some((min, max)) if max.unwrap_or(min) > MAX_REPEAT => Err(format!(
+                "{{{}}} repeats more than {} times",
+                text, MAX_REPEAT
+            )),
+            Some(bounds) => Ok(bounds),
+        }
+    }
+
+    fn atom(&mut self) -> Result<Ast, String> {
+        let at = self.pos;
+        let Some(c) = self.next() else {
+            return Err("unexpected end".to_string());
+        };
+        match c {
+            '(' => {
+                // Nothing is captured, so a non-capturing group is just a group
+                if self.eat('?') && !self.eat(':') {
+                    return Err(format!("unsupported group syntax at {}", at));
+                }
+                let inner = self.alternation()?;
+                if !self.eat(')') {
+                    return Err(format!("unclosed '(' at {}", at));
+                }
+                Ok(inner)
+            }
+            '[' => self.class(at),
+            '.' => Ok(Ast::Set(universe().collect())),
+            // The generated string is the whole match, so anchors always hold
+            '^' | '$' => Ok(Ast::Empty),
+            '*' | '+' | '?' | '{' => Err(format!("nothing to repeat at {}", at)),
+            '\\' => {
+                let Some(c) = self.next() else {
+                    return Err("trailing '\\'".to_string());
+                };
+                Ok(match (c, perl_class(c)) {
+                    (_, Some(set)) => Ast::Set(set.into_iter().collect()),
+                    ('b' | 'B', None) => Ast::Empty,
+                    (c, None) => Ast::Set(vec![escaped(c)]),
+                })
+            }
+            c => Ok(Ast::Set(vec![c])),
+        }
+    }
+
+    // A class after its `[`. A literal `]` inside has to be escaped, or the grammar scanner
+    // would take it for the end of the whole regex.
+    fn class(&mut self, at: usize) -> Result<Ast, String> {
+        let negated = self.eat('^');
+        let mut set = BTreeSet::new();
+        loop {
+            let Some(c) = self.next() else {
+                return Err(format!("unclosed '[' at {}", at));
+            };
+            if c == ']' {
+                break;
+            }
+
+            let low = match c {
+                '\\' => {
+                    let Some(c) = self.next() else {
+                        return Err("trailing '\\'".to_string());
+                    };
+                    if let Some(class) = perl_class(c) {
+                        set.extend(class);
+                        continue;
+                    }
+                    escaped(c)
+                }
+                c => c,
+            };
+            // `-` right before the closing `]` is a literal
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']') {
+                self.pos += 1;
+                let high = match self.next() {
+                    Some('\\') => self.next().map(escaped),
+                    high => high,
+                };
+                let Some(high) = high else {
+                    return Err(format!("unclosed '[' at {}", at));
+                };
+                if high < low {
+                    return Err(format!("range {}-{} is reversed", low, high));
+                }
+                set.extend(low..=high);
+            } else {
+                set.insert(low);
+            }
+        }
+
+        let chars: Vec<char> = if negated {
+            universe().filter(|c| !set.contains(c)).collect()
+        } else {
+            set.into_iter().collect()
+        };
+        if chars.is_empty() {
+            return Err(format!("class at {} matches nothing", at));
+        }
+        Ok(Ast::Set(chars))
+    }
+}
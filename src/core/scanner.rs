@@ -1,3 +1,7 @@
+use std::sync::Arc;
+
+use crate::core::diagnostic::{Diagnostic, Span};
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TokenType {
     OneOrMore,   // +
@@ -12,6 +16,7 @@ pub enum TokenType {
     Character,   // '...'
     Probability, // <...>
     Regex,       // [...]
+    Repeat,      // {...}
     Identifier,  // variable names
 }
 
@@ -19,42 +24,77 @@ pub enum TokenType {
 pub struct Token {
     pub typ: TokenType,
     pub text: String,
+    pub span: Span,
 }
 
 impl Token {
-    fn new(_: usize, typ: TokenType, text: String) -> Self {
-        Token { typ, text }
+    fn new(span: Span, typ: TokenType, text: String) -> Self {
+        Token { typ, text, span }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct ScanError {
-    _msg: String,
+    msg: String,
+    span: Span,
 }
 
 impl ScanError {
-    fn new(msg: String) -> Self {
-        ScanError { _msg: msg }
+    fn new(msg: String, span: Span) -> Self {
+        ScanError { msg, span }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.msg
+    }
+
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+}
+
+impl From<ScanError> for Diagnostic {
+    fn from(err: ScanError) -> Self {
+        Diagnostic::error(err.msg, err.span)
     }
 }
 
+// Where a char sits in the source
+#[derive(Debug, Clone, Copy)]
+struct Mark {
+    offset: usize,
+    line: usize,
+    column: usize,
+}
+
 pub struct Scanner {
-    pos: usize,   // current byte offset
+    pos: usize,   // current char index
     width: usize, // width of last char in bytes
     curr_r: char, // current rune/char
     tokens: Vec<Token>,
     chars: Vec<char>, // cached char array for easier iteration
+    file: Option<Arc<str>>,
+    here: Mark, // position of the next char
+    last: Mark, // position of the char returned by the last next()
 }
 
 impl Scanner {
-    pub fn new(input: String) -> Self {
+    pub fn new(input: &str, file: Option<Arc<str>>) -> Self {
         let chars: Vec<char> = input.chars().collect();
+        let start = Mark {
+            offset: 0,
+            line: 1,
+            column: 1,
+        };
         Scanner {
             pos: 0,
             width: 0,
             curr_r: '\0',
             tokens: Vec::new(),
             chars,
+            file,
+            here: start,
+            last: start,
         }
     }
 
@@ -63,16 +103,51 @@ impl Scanner {
         if self.pos >= self.chars.len() {
             self.width = 0;
             self.curr_r = '\0';
+            self.last = self.here;
             return None;
         }
 
         let c = self.chars[self.pos];
-        self.width = 1;
+        self.width = c.len_utf8();
         self.pos += 1;
         self.curr_r = c;
+
+        self.last = self.here;
+        self.here.offset += self.width;
+        if c == '\n' {
+            self.here.line += 1;
+            self.here.column = 1;
+        } else {
+            self.here.column += 1;
+        }
         Some(c)
     }
 
+    // span from `start` up to the end of the last consumed char
+    fn span_from(&self, start: Mark) -> Span {
+        Span {
+            file: self.file.clone(),
+            line: start.line,
+            column: start.column,
+            start: start.offset,
+            end: self.here.offset,
+        }
+    }
+
+    fn push(&mut self, start: Mark, typ: TokenType, text: String) {
+        let span = self.span_from(start);
+        self.tokens.push(Token::new(span, typ, text));
+    }
+
+    fn skip_line(&mut self) {
+        while let Some(r) = self.peek() {
+            if r == '\n' {
+                break;
+            }
+            self.next();
+        }
+    }
+
     // look at the next char without consuming it
     fn peek(&mut self) -> Option<char> {
         if self.pos >= self.chars.len() {
@@ -81,22 +156,41 @@ impl Scanner {
         Some(self.chars[self.pos])
     }
 
+    // Reads up to the next `close`. With `regex` pairs of `open` and `close` inside nest, and
+    // a backslash keeps the char after it from counting as either. The text comes back with
+    // its escapes left in.
     fn scan_delimited(
         &mut self,
         open: char,
         close: char,
-        _allow_escapes: bool,
+        regex: bool,
     ) -> Result<String, ScanError> {
+        let start = self.last;
         let mut buf = String::new();
+        let mut depth = 0;
 
         loop {
             match self.next() {
                 None => {
-                    return Err(ScanError::new(format!("unterminated '{}'", open)));
+                    return Err(ScanError::new(
+                        format!("unterminated '{}'", open),
+                        self.span_from(start),
+                    ));
                 }
                 Some(r) => {
                     if r == close {
-                        return Ok(buf);
+                        if depth == 0 {
+                            return Ok(buf);
+                        }
+                        depth -= 1;
+                    } else if r == open && regex {
+                        depth += 1;
+                    } else if r == '\\' && regex {
+                        buf.push(r);
+                        if let Some(r) = self.next() {
+                            buf.push(r);
+                        }
+                        continue;
                     }
                     buf.push(r);
                 }
@@ -104,6 +198,18 @@ impl Scanner {
         }
     }
 
+    // Reads a `[...]` up to its first `]`, as it always has. A `[` inside makes it a regex
+    // with classes of its own, read again with those nested and escapes kept.
+    fn scan_class(&mut self) -> Result<String, ScanError> {
+        let (pos, here, last) = (self.pos, self.here, self.last);
+        let class = self.scan_delimited('[', ']', false)?;
+        if !class.contains('[') {
+            return Ok(class);
+        }
+        (self.pos, self.here, self.last) = (pos, here, last);
+        self.scan_delimited('[', ']', true)
+    }
+
     fn scan_identifier(&mut self) -> String {
         let mut buf = String::new();
         buf.push(self.curr_r);
@@ -124,96 +230,46 @@ impl Scanner {
 
     pub fn scan(mut self) -> (Vec<Token>, Vec<ScanError>) {
         let mut errs = Vec::new();
+        // Only blanks so far on this line, where a `//` comment may start
+        let mut line_start = true;
 
         while let Some(c) = self.next() {
+            let start = self.last;
+            let at_line_start = line_start;
+            line_start = c == '\n' || (line_start && c.is_whitespace());
             match c {
-                '+' => {
-                    self.tokens.push(Token::new(
-                        self.pos - 1,
-                        TokenType::OneOrMore,
-                        String::new(),
-                    ));
-                }
-                '*' => {
-                    self.tokens
-                        .push(Token::new(self.pos - 1, TokenType::AnyNo, String::new()));
-                }
-                '^' => {
-                    self.tokens
-                        .push(Token::new(self.pos - 1, TokenType::Infinite, String::new()));
-                }
-                '?' => {
-                    self.tokens
-                        .push(Token::new(self.pos - 1, TokenType::Maybe, String::new()));
-                }
-                '|' => {
-                    self.tokens
-                        .push(Token::new(self.pos - 1, TokenType::Option, String::new()));
-                }
-                ';' => {
-                    self.tokens
-                        .push(Token::new(self.pos - 1, TokenType::Padding, String::new()));
-                }
-                '(' => {
-                    self.tokens
-                        .push(Token::new(self.pos - 1, TokenType::BracOpen, String::new()));
-                }
-                ')' => {
-                    self.tokens.push(Token::new(
-                        self.pos - 1,
-                        TokenType::BracClose,
-                        String::new(),
-                    ));
-                }
-                ':' => {
-                    self.tokens
-                        .push(Token::new(self.pos - 1, TokenType::Colon, String::new()));
-                }
+                '+' => self.push(start, TokenType::OneOrMore, String::new()),
+                '*' => self.push(start, TokenType::AnyNo, String::new()),
+                '^' => self.push(start, TokenType::Infinite, String::new()),
+                '?' => self.push(start, TokenType::Maybe, String::new()),
+                '|' => self.push(start, TokenType::Option, String::new()),
+                ';' => self.push(start, TokenType::Padding, String::new()),
+                '(' => self.push(start, TokenType::BracOpen, String::new()),
+                ')' => self.push(start, TokenType::BracClose, String::new()),
+                ':' => self.push(start, TokenType::Colon, String::new()),
+                // A comment fills its line, a `//` after anything else is left alone
+                '/' if at_line_start && self.peek() == Some('/') => self.skip_line(),
                 '\'' => match self.scan_delimited('\'', '\'', false) {
-                    Ok(val) => {
-                        self.tokens.push(Token::new(
-                            self.pos - val.len() - 2,
-                            TokenType::Character,
-                            val,
-                        ));
-                    }
-                    Err(err) => {
-                        errs.push(err);
-                    }
+                    Ok(val) => self.push(start, TokenType::Character, val),
+                    Err(err) => errs.push(err),
                 },
                 '<' => match self.scan_delimited('<', '>', false) {
-                    Ok(val) => {
-                        self.tokens.push(Token::new(
-                            self.pos - val.len() - 2,
-                            TokenType::Probability,
-                            val,
-                        ));
-                    }
-                    Err(err) => {
-                        errs.push(err);
-                    }
+                    Ok(val) => self.push(start, TokenType::Probability, val),
+                    Err(err) => errs.push(err),
                 },
-                '[' => match self.scan_delimited('[', ']', false) {
-                    Ok(val) => {
-                        self.tokens.push(Token::new(
-                            self.pos - val.len() - 2,
-                            TokenType::Regex,
-                            val,
-                        ));
-                    }
-                    Err(err) => {
-                        errs.push(err);
-                    }
+                '[' => match self.scan_class() {
+                    Ok(val) => self.push(start, TokenType::Regex, val),
+                    Err(err) => errs.push(err),
+                },
+                '{' => match self.scan_delimited('{', '}', false) {
+                    Ok(val) => self.push(start, TokenType::Repeat, val),
+                    Err(err) => errs.push(err),
                 },
                 _ => {
                     if is_ident_start(c) {
                         let buff = self.scan_identifier();
                         if !buff.is_empty() {
-                            self.tokens.push(Token::new(
-                                self.pos - buff.len(),
-                                TokenType::Identifier,
-                                buff,
-                            ));
+                            self.push(start, TokenType::Identifier, buff);
                         }
                     }
                     // Ignore whitespace and other characters
@@ -241,3 +297,57 @@ fn is_ident_start(r: char) -> bool {
 fn is_ident_part(r: char) -> bool {
     is_alpha(r) || is_digit(r) || r == '_'
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(input: &str) -> Vec<(TokenType, String)> {
+        let (tokens, errs) = Scanner::new(input, None).scan();
+        assert!(errs.is_empty());
+        tokens.into_iter().map(|t| (t.typ, t.text)).collect()
+    }
+
+    #[test]
+    fn classes_nest_only_in_regexes() {
+        assert_eq!(
+            scan("s: [a-z];"),
+            vec![
+                (TokenType::Identifier, "s".to_string()),
+                (TokenType::Colon, String::new()),
+                (TokenType::Regex, "a-z".to_string()),
+                (TokenType::Padding, String::new()),
+            ],
+        );
+        assert_eq!(
+            scan("[0x[0-9a-f]{2,8}] [x[\\]]]"),
+            vec![
+                (TokenType::Regex, "0x[0-9a-f]{2,8}".to_string()),
+                (TokenType::Regex, "x[\\]]".to_string()),
+            ],
+        );
+        assert_eq!(
+            scan("[(cat|dog)s?]<regex> [\\]"),
+            vec![
+                (TokenType::Regex, "(cat|dog)s?".to_string()),
+                (TokenType::Probability, "regex".to_string()),
+                (TokenType::Regex, "\\".to_string()),
+            ],
+        );
+    }
+
+    #[test]
+    fn comments_only_fill_whole_lines() {
+        assert_eq!(scan("// header\n  // indented\ns: 'a';"), scan("s: 'a';"),);
+        assert_eq!(
+            scan("s: /'/' //'/';"),
+            vec![
+                (TokenType::Identifier, "s".to_string()),
+                (TokenType::Colon, String::new()),
+                (TokenType::Character, "/".to_string()),
+                (TokenType::Character, "/".to_string()),
+                (TokenType::Padding, String::new()),
+            ],
+        );
+    }
+}
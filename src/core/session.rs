@@ -0,0 +1,162 @@
+use crate::core::{
+    codec::{Reader, Writer},
+    error::ResrapError,
+    frozen_graph::{FrozenSyntaxGraph, Termination},
+    prng::PRNG,
+    walker::{Step, WalkState, Walker},
+};
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"RSRS";
+const SNAPSHOT_VERSION: u8 = 5;
+
+/// A paused generation session, see `GenerationSession::save`.
+/// `to_bytes` turns it into a compact blob that can be stored anywhere and restored in
+/// another process, as long as the grammar it is restored on is unchanged.
+#[derive(Clone)]
+pub struct SessionState {
+    walk: WalkState,
+    fingerprint: u64,
+}
+
+impl SessionState {
+    /// Encodes the state as versioned bytes, see `from_bytes`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Writer::new();
+        out.buf.extend_from_slice(SNAPSHOT_MAGIC);
+        out.u8(SNAPSHOT_VERSION);
+        out.u64(self.fingerprint);
+        self.walk.encode(&mut out);
+        out.buf
+    }
+
+    /// Decodes bytes made by `to_bytes`.
+    /// Whether they fit the grammar is only checked once the state is restored.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ResrapError> {
+        let mut input = Reader::new(bytes);
+        let invalid = ResrapError::InvalidSnapshot;
+
+        if input.bytes(SNAPSHOT_MAGIC.len()).ok() != Some(SNAPSHOT_MAGIC.as_slice()) {
+            return Err(invalid("not a resrap session".to_string()));
+        }
+        let version = input.u8().map_err(invalid)?;
+        if version != SNAPSHOT_VERSION {
+            return Err(invalid(format!("unsupported version {}", version)));
+        }
+        let fingerprint = input.u64().map_err(invalid)?;
+        let walk = WalkState::decode(&mut input).map_err(invalid)?;
+        if !input.is_empty() {
+            return Err(invalid("trailing bytes".to_string()));
+        }
+        Ok(SessionState { walk, fingerprint })
+    }
+}
+
+/// One long derivation handed out in chunks.
+/// Every `next_chunk` continues exactly where the previous one stopped, so the chunks joined
+/// together are the same tokens a single walk with the same seed would produce.
+pub struct GenerationSession<'g> {
+    walker: Walker<'g>,
+}
+
+impl<'g> GenerationSession<'g> {
+    pub fn new(
+        graph: &'g FrozenSyntaxGraph,
+        prng: PRNG,
+        start: String,
+    ) -> Result<Self, ResrapError> {
+        Ok(GenerationSession {
+            walker: Walker::new(graph, prng, start, usize::MAX, Termination::Truncate)?,
+        })
+    }
+
+    /// Continues a saved session.
+    /// Fails with `GrammarChanged` if `state` was saved on a graph built from another grammar.
+    pub fn restore(graph: &'g FrozenSyntaxGraph, state: SessionState) -> Result<Self, ResrapError> {
+        if state.fingerprint != graph.fingerprint {
+            return Err(ResrapError::GrammarChanged);
+        }
+        let mut walk = state.walk;
+        walk.attach(graph)?;
+        Ok(GenerationSession {
+            walker: Walker::resume(graph, walk),
+        })
+    }
+
+    /// Generates up to `tokens` more tokens. Fewer come back only once the derivation is over.
+    pub fn next_chunk(&mut self, tokens: usize) -> Result<Vec<String>, ResrapError> {
+        let mut chunk = Vec::with_capacity(tokens);
+        while chunk.len() < tokens {
+            match self.walker.step()? {
+                Step::Emit(text, _) => chunk.push(text),
+                Step::Done => break,
+                Step::Enter(_) | Step::Exit => {}
+            }
+        }
+        Ok(chunk)
+    }
+
+    /// True once the derivation is over and no more tokens will come.
+    pub fn is_finished(&self) -> bool {
+        self.walker.state().is_done()
+    }
+
+    /// Captures where the session is, to `restore` it later.
+    pub fn save(&self) -> SessionState {
+        SessionState {
+            walk: self.walker.state().clone(),
+            fingerprint: self.walker.graph().fingerprint,
+        }
+    }
+}
+
+impl FrozenSyntaxGraph {
+    /// Starts a chunked generation session from `start`, see `GenerationSession`.
+    pub fn session(&self, prng: PRNG, start: String) -> Result<GenerationSession<'_>, ResrapError> {
+        GenerationSession::new(self, prng, start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Resrap;
+
+    fn load(grammar: &str) -> Resrap {
+        let mut resrap = Resrap::new();
+        resrap
+            .parse_grammar("g".to_string(), grammar.to_string())
+            .unwrap();
+        resrap
+    }
+
+    fn saved(resrap: &Resrap) -> Vec<u8> {
+        let mut session = resrap.session("g", "s".to_string(), 7).unwrap();
+        session.next_chunk(3).unwrap();
+        session.save().to_bytes()
+    }
+
+    #[test]
+    fn restore_needs_the_same_grammar() {
+        let resrap = load("s: ('a' | 'b')^;");
+        let bytes = saved(&resrap);
+        assert!(resrap.restore_session_bytes("g", &bytes).is_ok());
+
+        let changed = load("s: ('a' | 'c')^;");
+        assert!(matches!(
+            changed.restore_session_bytes("g", &bytes),
+            Err(ResrapError::GrammarChanged)
+        ));
+    }
+
+    #[test]
+    fn restore_needs_the_same_version() {
+        let resrap = load("s: ('a' | 'b')^;");
+        let mut bytes = saved(&resrap);
+        bytes[SNAPSHOT_MAGIC.len()] = SNAPSHOT_VERSION + 1;
+        match resrap.restore_session_bytes("g", &bytes) {
+            Err(ResrapError::InvalidSnapshot(msg)) => assert!(msg.contains("version"), "{}", msg),
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(_) => panic!("a snapshot from another version was restored"),
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, ready},
+    vec,
+};
+
+use tokio::{sync::mpsc, task};
+use tokio_stream::Stream;
+
+use crate::core::{
+    error::ResrapError, frozen_graph::FrozenSyntaxGraph, prng::PRNG, session::GenerationSession,
+};
+
+// Tokens generated per trip through the channel, and trips that can wait unread
+const CHUNK: usize = 1024;
+const BUFFERED_CHUNKS: usize = 4;
+
+/// Tokens of one generation, produced on tokio's blocking pool while they are consumed.
+/// Generation stays a few chunks ahead of the reader and stops once the stream is dropped.
+pub struct TokenStream {
+    chunks: mpsc::Receiver<Result<Vec<String>, ResrapError>>,
+    current: vec::IntoIter<String>,
+}
+
+impl Stream for TokenStream {
+    type Item = Result<String, ResrapError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(token) = self.current.next() {
+                return Poll::Ready(Some(Ok(token)));
+            }
+            match ready!(self.chunks.poll_recv(cx)) {
+                Some(Ok(chunk)) => self.current = chunk.into_iter(),
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// `walk_graph` run on tokio's blocking pool so the executor stays free.
+pub async fn walk_graph_async(
+    graph: Arc<FrozenSyntaxGraph>,
+    prng: PRNG,
+    start: String,
+    tokens: usize,
+) -> Result<Vec<String>, ResrapError> {
+    match task::spawn_blocking(move || graph.walk_graph(prng, start, tokens)).await {
+        Ok(result) => result,
+        Err(err) => std::panic::resume_unwind(err.into_panic()),
+    }
+}
+
+/// Streams the same tokens `walk_graph` would return, generated in chunks by a session.
+/// Has to be called from inside a tokio runtime.
+pub fn token_stream(
+    graph: Arc<FrozenSyntaxGraph>,
+    prng: PRNG,
+    start: String,
+    tokens: usize,
+) -> Result<TokenStream, ResrapError> {
+    // Set the session up here so a bad start rule fails right away instead of mid-stream
+    let state = graph.session(prng, start)?.save();
+    let (tx, rx) = mpsc::channel(BUFFERED_CHUNKS);
+
+    task::spawn_blocking(move || {
+        let mut session = match GenerationSession::restore(&graph, state) {
+            Ok(session) => session,
+            Err(err) => {
+                let _ = tx.blocking_send(Err(err));
+                return;
+            }
+        };
+        let mut remaining = tokens;
+        while remaining > 0 && !session.is_finished() {
+            let chunk = session.next_chunk(remaining.min(CHUNK));
+            let failed = chunk.is_err();
+            if let Ok(chunk) = &chunk {
+                remaining -= chunk.len();
+            }
+            // A failed send means the stream was dropped
+            if tx.blocking_send(chunk).is_err() || failed {
+                return;
+            }
+        }
+    });
+
+    Ok(TokenStream {
+        chunks: rx,
+        current: Vec::new().into_iter(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime;
+    use tokio_stream::StreamExt;
+
+    use crate::Resrap;
+
+    #[test]
+    fn stream_matches_generate_with_seed() {
+        let mut resrap = Resrap::new();
+        resrap
+            .parse_grammar("g".to_string(), "s: ('a' | 'b' | [c-e])^;".to_string())
+            .unwrap();
+        let tokens = 3 * super::CHUNK + 7;
+        let expected = resrap
+            .generate_with_seed("g", "s".to_string(), 42, tokens)
+            .unwrap();
+
+        let streamed: Vec<String> = runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let stream = resrap.generate_stream("g", "s".to_string(), 42, tokens)?;
+                stream.collect::<Result<Vec<_>, _>>().await
+            })
+            .unwrap();
+        assert_eq!(streamed, expected);
+    }
+}
@@ -0,0 +1,517 @@
+use crate::core::{
+    codec::{Reader, Writer},
+    error::ResrapError,
+    frozen_graph::{FrozenSyntaxGraph, FrozenSyntaxNode, NO_INDEX, Termination},
+    graph::NodeType,
+    prng::PRNG,
+};
+
+/// Where a generated token came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// A quoted literal, `'...'`.
+    Literal,
+    /// A regex class, `[...]`.
+    Regex,
+}
+
+/// One observable thing a walk does.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    /// Descended into the rule with this ID.
+    Enter(u32),
+    /// Finished the innermost open rule and returned to its caller.
+    Exit,
+    /// Printed a token.
+    Emit(String, Origin),
+    /// The walk is over, every further call returns this again.
+    Done,
+}
+
+// A counted loop that is going round, see `NodeType::REPEAT`
+#[derive(Clone, Copy)]
+struct OpenLoop {
+    node: u32,
+    // Frames on the rule stack when it started, a loop belongs to the frame it started in
+    depth: u32,
+    // Rounds of the body finished so far
+    laps: u32,
+    // Rounds it was drawn to go when it started, if its count comes from a distribution
+    target: Option<u32>,
+}
+
+/// Everything a walk needs to carry on, apart from the graph itself.
+#[derive(Clone)]
+pub struct WalkState {
+    prng: PRNG,
+    // Node indices, not IDs
+    current: u32,
+    // Literals of the current node already printed, see `optimize`
+    run: u32,
+    graph_stack: Vec<u32>,
+    // Counted loops still going round, innermost last. They are per frame, so a rule that
+    // calls itself from inside a loop counts its own rounds.
+    loops: Vec<OpenLoop>,
+    // Fewest tokens the return addresses on the stack still have to print
+    pending: usize,
+    printed_tokens: usize,
+    tokens: usize,
+    termination: Termination,
+    // Set once a bounded walk made a choice that would overrun the budget. From then on every
+    // rule finishes the shortest way, so it can't keep coming back to choices that don't fit.
+    finishing: bool,
+    done: bool,
+}
+
+impl WalkState {
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    // `pending` is left out, it follows from the stack and is rebuilt by `attach`
+    pub(crate) fn encode(&self, out: &mut Writer) {
+        let (seed, number) = self.prng.state();
+        out.u64(seed);
+        out.u64(number);
+        out.u32(self.current);
+        out.u32(self.run);
+        out.u32(self.graph_stack.len() as u32);
+        for &id in &self.graph_stack {
+            out.u32(id);
+        }
+        out.u32(self.loops.len() as u32);
+        for open in &self.loops {
+            out.u32(open.node);
+            out.u32(open.depth);
+            out.u32(open.laps);
+            out.u8(open.target.is_some() as u8);
+            out.u32(open.target.unwrap_or(0));
+        }
+        out.u64(self.printed_tokens as u64);
+        out.u64(self.tokens as u64);
+        match self.termination {
+            Termination::Truncate => out.u8(0),
+            Termination::Bounded { max_depth } => {
+                out.u8(1);
+                out.u64(max_depth as u64);
+            }
+            Termination::Complete => out.u8(2),
+        }
+        out.u8(self.done as u8);
+        out.u8(self.finishing as u8);
+    }
+
+    pub(crate) fn decode(input: &mut Reader) -> Result<Self, String> {
+        // Counts only saturate when a 64 bit snapshot is read on a smaller platform
+        let size = |v: u64| usize::try_from(v).unwrap_or(usize::MAX);
+
+        let prng = PRNG::from_state(input.u64()?, input.u64()?);
+        let current = input.u32()?;
+        let run = input.u32()?;
+        let depth = input.u32()?;
+        let mut graph_stack = Vec::new();
+        for _ in 0..depth {
+            graph_stack.push(input.u32()?);
+        }
+        let count = input.u32()?;
+        let mut loops = Vec::new();
+        for _ in 0..count {
+            let (node, depth, laps) = (input.u32()?, input.u32()?, input.u32()?);
+            let drawn = input.u8()?;
+            let target = input.u32()?;
+            let target = match drawn {
+                0 => None,
+                1 => Some(target),
+                flag => return Err(format!("bad loop target flag {}", flag)),
+            };
+            loops.push(OpenLoop {
+                node,
+                depth,
+                laps,
+                target,
+            });
+        }
+        let printed_tokens = size(input.u64()?);
+        let tokens = size(input.u64()?);
+        let termination = match input.u8()? {
+            0 => Termination::Truncate,
+            1 => Termination::Bounded {
+                max_depth: size(input.u64()?),
+            },
+            2 => Termination::Complete,
+            tag => return Err(format!("unknown termination mode {}", tag)),
+        };
+        let done = match input.u8()? {
+            0 => false,
+            1 => true,
+            flag => return Err(format!("bad done flag {}", flag)),
+        };
+        let finishing = match input.u8()? {
+            0 => false,
+            1 => true,
+            flag => return Err(format!("bad finishing flag {}", flag)),
+        };
+
+        Ok(WalkState {
+            prng,
+            current,
+            run,
+            graph_stack,
+            loops,
+            pending: 0,
+            printed_tokens,
+            tokens,
+            termination,
+            finishing,
+            done,
+        })
+    }
+
+    // Checks a decoded state against the graph it is about to run on and rebuilds `pending`
+    pub(crate) fn attach(&mut self, graph: &FrozenSyntaxGraph) -> Result<(), ResrapError> {
+        let check = |index: u32| match graph.nodes.get(index as usize) {
+            Some(node) => Ok(node),
+            None => Err(ResrapError::InvalidSnapshot(format!(
+                "node index {} out of range",
+                index
+            ))),
+        };
+        let node = check(self.current)?;
+        if self.run > 0 && self.run >= node.print_len {
+            return Err(ResrapError::InvalidSnapshot(format!(
+                "literal {} past the end of node {}",
+                self.run, self.current
+            )));
+        }
+        let mut depth = 0;
+        for open in &self.loops {
+            if check(open.node)?.typ != NodeType::REPEAT
+                || open.depth < depth
+                || open.depth as usize > self.graph_stack.len()
+            {
+                return Err(ResrapError::InvalidSnapshot(format!(
+                    "loop at node {} doesn't fit the rule stack",
+                    open.node
+                )));
+            }
+            depth = open.depth;
+        }
+        let mut pending = 0usize;
+        for &index in &self.graph_stack {
+            pending = pending.saturating_add(check(index)?.min_tokens as usize);
+        }
+        self.pending = pending;
+        Ok(())
+    }
+}
+
+/// A walk over a frozen graph that hands out one step at a time.
+/// All the walking state lives in a `WalkState`, so a walk can be paused between steps.
+pub struct Walker<'g> {
+    graph: &'g FrozenSyntaxGraph,
+    state: WalkState,
+}
+
+impl<'g> Walker<'g> {
+    pub fn new(
+        graph: &'g FrozenSyntaxGraph,
+        prng: PRNG,
+        start: String,
+        tokens: usize,
+        termination: Termination,
+    ) -> Result<Self, ResrapError> {
+        let Some(&start_id) = graph.name_map.get(&start) else {
+            return Err(ResrapError::UnknownStartRule(start));
+        };
+        let start_index = graph.index_of(start_id)?;
+        if termination != Termination::Truncate && graph.at(start_index).min_tokens == u32::MAX {
+            return Err(ResrapError::UnfinishableRule(start));
+        }
+
+        let state = WalkState {
+            prng,
+            current: start_index,
+            run: 0,
+            graph_stack: vec![],
+            loops: vec![],
+            pending: 0,
+            printed_tokens: 0,
+            tokens,
+            termination,
+            finishing: false,
+            done: false,
+        };
+        Ok(Walker { graph, state })
+    }
+
+    /// Picks a paused walk back up. `state` has to come from a walk over the same graph.
+    pub fn resume(graph: &'g FrozenSyntaxGraph, state: WalkState) -> Self {
+        Walker { graph, state }
+    }
+
+    pub fn state(&self) -> &WalkState {
+        &self.state
+    }
+
+    pub fn graph(&self) -> &'g FrozenSyntaxGraph {
+        self.graph
+    }
+
+    /// Advances the walk until it does something observable.
+    pub fn step(&mut self) -> Result<Step, ResrapError> {
+        let graph = self.graph;
+        loop {
+            if self.state.done {
+                return Ok(Step::Done);
+            }
+            let current = graph.at(self.state.current);
+
+            if self.state.termination == Termination::Truncate
+                && self.state.printed_tokens >= self.state.tokens
+            {
+                self.state.done = true;
+                continue;
+            }
+
+            let mut step = None;
+            match current.typ {
+                NodeType::CH => {
+                    if let Some(text) = graph.texts(current).get(self.state.run as usize) {
+                        self.state.printed_tokens += 1;
+                        self.state.run += 1;
+                        // Folded literals come out one token at a time, staying on the node
+                        if self.state.run < current.print_len {
+                            return Ok(Step::Emit(text.clone(), Origin::Literal));
+                        }
+                        self.state.run = 0;
+                        step = Some(Step::Emit(text.clone(), Origin::Literal));
+                    }
+                }
+                NodeType::RX if current.print != NO_INDEX => {
+                    let content = graph.regexer.generate(current.print, &mut self.state.prng);
+                    step = Some(Step::Emit(content, Origin::Regex));
+                    self.state.printed_tokens += 1;
+                }
+                NodeType::POINTER => {
+                    if let Some(&ret_node) = graph.edges(current).first() {
+                        if current.callee == NO_INDEX {
+                            return Err(ResrapError::DanglingNode(current.pointer));
+                        }
+                        self.state.graph_stack.push(ret_node);
+                        self.state.pending += graph.at(ret_node).min_tokens as usize;
+                        self.state.current = current.callee;
+                        return Ok(Step::Enter(current.pointer));
+                    }
+                    continue;
+                }
+                NodeType::REPEAT => {
+                    self.state.current = self.repeat(current);
+                    continue;
+                }
+                NodeType::END => {
+                    if let Some(ret_node) = self.state.graph_stack.pop() {
+                        self.state.pending -= graph.at(ret_node).min_tokens as usize;
+                        self.state.current = ret_node;
+                        return Ok(Step::Exit);
+                    }
+                    if self.state.termination == Termination::Complete
+                        && self.state.printed_tokens >= self.state.tokens
+                    {
+                        self.state.done = true;
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+
+            match self.choose(current) {
+                Some(next) => self.state.current = next,
+                None => self.state.done = true,
+            }
+            if let Some(step) = step {
+                return Ok(step);
+            }
+        }
+    }
+
+    // Counts the round that brought the walk back to the loop at `current`, or opens the loop
+    // if it just got there and draws its count if it has a distribution. Then picks between
+    // going round again and leaving.
+    fn repeat(&mut self, current: &FrozenSyntaxNode) -> u32 {
+        let graph = self.graph;
+        let index = self.state.current;
+        let depth = self.state.graph_stack.len() as u32;
+        let bounds = &graph.repeats[current.print as usize];
+        let open = match self.state.loops.last_mut() {
+            Some(open) if open.node == index && open.depth == depth => {
+                open.laps += 1;
+                *open
+            }
+            _ => {
+                let target = bounds.count.as_ref().map(|count| {
+                    let drawn = count.sample(&mut self.state.prng).max(bounds.min);
+                    bounds.max.map_or(drawn, |max| drawn.min(max))
+                });
+                let open = OpenLoop {
+                    node: index,
+                    depth,
+                    laps: 0,
+                    target,
+                };
+                self.state.loops.push(open);
+                open
+            }
+        };
+
+        let (body, exit) = (graph.edges(current)[0], graph.edges(current)[1]);
+        let again = if open.laps < bounds.min {
+            true
+        } else if bounds.max.is_some_and(|max| open.laps >= max) {
+            false
+        } else {
+            let wanted = match open.target {
+                Some(target) => open.laps < target,
+                None => self.state.prng.random() as f32 <= graph.frequencies(current)[0],
+            };
+            wanted && self.lap_fits(current, body, exit)
+        };
+
+        if again {
+            body
+        } else {
+            self.state.loops.pop();
+            exit
+        }
+    }
+
+    // Whether a bounded walk can afford one more optional round of a loop and still finish. A
+    // complete walk stops going round once it has reached its target.
+    fn lap_fits(&self, current: &FrozenSyntaxNode, body: u32, exit: u32) -> bool {
+        let max_depth = match self.state.termination {
+            Termination::Truncate => return true,
+            Termination::Complete => return self.state.printed_tokens < self.state.tokens,
+            Termination::Bounded { max_depth } => max_depth,
+        };
+        let graph = self.graph;
+        // The body's completion goes round once and then pays for the loop from scratch
+        let lap = graph.at(body).min_tokens;
+        if self.state.finishing || lap == u32::MAX || self.state.graph_stack.len() >= max_depth {
+            return false;
+        }
+        let projected = (self.state.printed_tokens + self.state.pending)
+            .saturating_add(lap.saturating_sub(current.min_tokens) as usize)
+            .saturating_add(graph.at(exit).min_tokens as usize);
+        projected <= self.state.tokens
+    }
+
+    // Picks the node to move to from `current`, None if the walk ends here
+    fn choose(&mut self, current: &FrozenSyntaxNode) -> Option<u32> {
+        let graph = self.graph;
+        let edges = graph.edges(current);
+        if edges.is_empty() {
+            return None;
+        }
+
+        // A single way out is taken without drawing, it could only ever pick that one
+        let mut index = if edges.len() == 1 {
+            0
+        } else {
+            let value = self.state.prng.random() as f32;
+            let frequencies = graph.frequencies(current);
+            match frequencies.iter().position(|&x| x >= value) {
+                Some(i) => i,
+                None => frequencies.len() - 1,
+            }
+        };
+
+        if let Termination::Bounded { max_depth } = self.state.termination {
+            let chosen = graph.at(edges[index]);
+            // A finished start rule only loops (`^`) again if a full round still fits
+            let finished = current.typ == NodeType::END;
+            let projected = (self.state.printed_tokens + self.state.pending)
+                .saturating_add(chosen.min_tokens as usize);
+            if chosen.min_tokens != u32::MAX && projected > self.state.tokens {
+                self.state.finishing = true;
+            }
+            if self.state.finishing
+                || self.state.graph_stack.len() >= max_depth
+                || chosen.min_tokens == u32::MAX
+            {
+                if finished {
+                    return None;
+                }
+                index = graph.shortest_option(current);
+            }
+        } else if self.state.termination == Termination::Complete {
+            // Past the target every open rule heads for its END the shortest way, and no choice
+            // may lead somewhere that never ends
+            if self.state.printed_tokens >= self.state.tokens
+                || graph.at(edges[index]).min_tokens == u32::MAX
+            {
+                index = graph.shortest_option(current);
+            }
+        }
+
+        Some(edges[index])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Resrap, ResrapError, Termination};
+
+    fn load(grammar: &str) -> Resrap {
+        let mut resrap = Resrap::new();
+        resrap
+            .parse_grammar("g".to_string(), grammar.to_string())
+            .unwrap();
+        resrap
+    }
+
+    #[test]
+    fn complete_walk_finishes_recursive_rules() {
+        let resrap = load("s: 'a' s | 'b';\nt: '(' t ')' t | ;");
+        for seed in 0..50 {
+            let done = resrap
+                .generate_complete_with_seed("g", "s".to_string(), seed, 5)
+                .unwrap();
+            assert_eq!(done.tokens.last().map(String::as_str), Some("b"));
+            // Past the target `s` takes its shortest way out right away
+            assert!(done.tokens.len() <= 6);
+            assert_eq!(done.overshoot, done.tokens.len().saturating_sub(5));
+
+            let done = resrap
+                .generate_complete_with_seed("g", "t".to_string(), seed, 20)
+                .unwrap();
+            let opened = done.tokens.iter().filter(|t| *t == "(").count();
+            assert_eq!(opened * 2, done.tokens.len());
+        }
+    }
+
+    #[test]
+    fn bounded_walk_ends_when_the_prng_is_stuck() {
+        // Seed 0 draws 0 every time, which always goes round `*` again when it fits
+        let resrap = load("s: '(' ('a' (',' 'a')*)? ')';");
+        for tokens in 1..30 {
+            let out = resrap
+                .generate_with_termination(
+                    "g",
+                    "s".to_string(),
+                    0,
+                    tokens,
+                    Termination::Bounded { max_depth: 8 },
+                )
+                .unwrap();
+            assert_eq!(out.last().map(String::as_str), Some(")"));
+        }
+    }
+
+    #[test]
+    fn complete_walk_rejects_unfinishable_rule() {
+        let resrap = load("s: 'a' s;");
+        let err = resrap
+            .generate_complete_with_seed("g", "s".to_string(), 1, 5)
+            .unwrap_err();
+        assert!(matches!(err, ResrapError::UnfinishableRule(rule) if rule == "s"));
+    }
+}
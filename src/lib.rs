@@ -1,7 +1,34 @@
 mod core;
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{
+        Mutex,
+        mpsc::{self, Receiver, Sender},
+    },
+};
 
-use crate::core::{file::Lang, prng::PRNG};
+use crate::core::{
+    file::Lang,
+    frozen_graph::FrozenSyntaxGraph,
+    pool::{Job, WorkerPool},
+    prng::PRNG,
+};
+
+pub use crate::core::annotated::AnnotatedToken;
+pub use crate::core::bias::{BiasProfile, BiasTable, CharBias};
+pub use crate::core::build;
+pub use crate::core::derivation::{Derivation, Leaves};
+pub use crate::core::diagnostic::{Diagnostic, Severity, Span};
+pub use crate::core::distribution::Distribution;
+pub use crate::core::error::ResrapError;
+pub use crate::core::file::LangConfig;
+pub use crate::core::frozen_graph::{Completed, Termination};
+pub use crate::core::generator::Generator;
+pub use crate::core::pool::{JobResult, PoolConfig, PoolStats};
+pub use crate::core::session::{GenerationSession, SessionState};
+#[cfg(feature = "tokio")]
+pub use crate::core::stream::TokenStream;
+pub use crate::core::walker::Origin;
 
 /// Resrap is the main access point for single-threaded uses.
 /// It's a collection of grammars which can be generated using parsing grammar.
@@ -22,34 +49,103 @@ impl Resrap {
     ///
     /// # Arguments
     /// * `name` - A unique identifier for this grammar (e.g., "C"), should be in ABNF format
-    ///            (Check osdc/resrap for more info on that).
+    ///   (Check osdc/resrap for more info on that).
     /// * `grammar` - The grammar string to parse
     ///
     /// # Returns
-    /// Returns error generated while parsing
-    pub fn parse_grammar(&mut self, name: String, grammar: String) -> Result<(), String> {
-        let mut lang = Lang::new();
-        let err = lang.parse_string(grammar);
+    /// Returns error generated while parsing. A grammar that fails to parse is not stored.
+    pub fn parse_grammar(&mut self, name: String, grammar: String) -> Result<(), ResrapError> {
+        self.parse_grammar_with_config(name, grammar, LangConfig::default())
+    }
+
+    /// Parses a grammar string with grammar-wide settings, see `parse_grammar`.
+    ///
+    /// # Arguments
+    /// * `config` - Defaults for the whole grammar, like how long regex strings are
+    pub fn parse_grammar_with_config(
+        &mut self,
+        name: String,
+        grammar: String,
+        config: LangConfig,
+    ) -> Result<(), ResrapError> {
+        let mut lang = Lang::with_config(config);
+        lang.parse_string(grammar)?;
 
-        self.language_graph.insert(name.clone(), lang);
-        err
+        self.language_graph.insert(name, lang);
+        Ok(())
     }
 
     /// Parses a grammar from a file and stores it under the given name.
     ///
     /// # Arguments
     /// * `name` - A unique identifier for this grammar (e.g., "C"), should be in ABNF format
-    ///            (Check osdc/resrap for more info on that).
+    ///   (Check osdc/resrap for more info on that).
     /// * `location` - Path to the grammar file
     ///
     /// # Returns
-    /// Returns error generated while parsing
-    pub fn parse_grammar_file(&mut self, name: String, location: String) -> Result<(), String> {
+    /// Returns error generated while reading or parsing. A grammar that fails to parse is not stored.
+    pub fn parse_grammar_file(
+        &mut self,
+        name: String,
+        location: String,
+    ) -> Result<(), ResrapError> {
+        self.parse_grammar_file_with_config(name, location, LangConfig::default())
+    }
+
+    /// Parses a grammar from a file with grammar-wide settings, see `parse_grammar_file`.
+    ///
+    /// # Arguments
+    /// * `config` - Defaults for the whole grammar, like how long regex strings are
+    pub fn parse_grammar_file_with_config(
+        &mut self,
+        name: String,
+        location: String,
+        config: LangConfig,
+    ) -> Result<(), ResrapError> {
+        let mut lang = Lang::with_config(config);
+        lang.parse_file(location)?;
+
+        self.language_graph.insert(name, lang);
+        Ok(())
+    }
+
+    /// Loads a grammar compiled with `compile_grammar` and stores it under the given name.
+    /// Nothing is scanned or parsed, the graph is used as it was saved.
+    ///
+    /// # Returns
+    /// `ResrapError::InvalidCompiledGrammar` if the bytes are damaged or from another version.
+    /// A grammar that fails to load is not stored.
+    pub fn load_compiled_grammar(&mut self, name: String, bytes: &[u8]) -> Result<(), ResrapError> {
+        let mut lang = Lang::new();
+        lang.load_compiled(bytes)?;
+
+        self.language_graph.insert(name, lang);
+        Ok(())
+    }
+
+    /// Loads a grammar from a file written by `save_compiled_grammar`, see `load_compiled_grammar`.
+    pub fn load_compiled_grammar_file(
+        &mut self,
+        name: String,
+        location: String,
+    ) -> Result<(), ResrapError> {
         let mut lang = Lang::new();
-        let err = lang.parse_file(location);
+        lang.load_compiled_file(location)?;
+
+        self.language_graph.insert(name, lang);
+        Ok(())
+    }
 
-        self.language_graph.insert(name.clone(), lang);
-        err
+    /// Returns the compiled form of a loaded grammar, a versioned and checksummed binary that
+    /// `load_compiled_grammar` reads back. Warnings and lints are not part of it.
+    pub fn compile_grammar(&self, name: &str) -> Result<Vec<u8>, ResrapError> {
+        Ok(self.graph(name)?.to_bytes())
+    }
+
+    /// Writes the compiled form of a loaded grammar to a file, see `compile_grammar`.
+    pub fn save_compiled_grammar(&self, name: &str, location: String) -> Result<(), ResrapError> {
+        std::fs::write(location, self.compile_grammar(name)?)?;
+        Ok(())
     }
 
     /// Generates content from the grammar identified by 'name' with a seed.
@@ -61,21 +157,234 @@ impl Resrap {
     /// * `tokens` - Number of tokens to generate
     ///
     /// # Returns
-    /// A string containing the generated content.
+    /// The generated tokens, or `ResrapError::UnknownGrammar` if no grammar is stored under `name`.
     pub fn generate_with_seed(
         &self,
         name: &str,
         starting_node: String,
         seed: u64,
         tokens: usize,
-    ) -> Result<Vec<String>, &str> {
+    ) -> Result<Vec<String>, ResrapError> {
+        let prng = PRNG::new(seed);
+        self.graph(name)?.walk_graph(prng, starting_node, tokens)
+    }
+
+    /// Generates content like `generate_with_seed`, with control over how the walk ends.
+    /// `Termination::Bounded` always produces a complete sentence of the grammar.
+    ///
+    /// # Arguments
+    /// * `name` - The grammar name to use
+    /// * `starting_node` - The starting symbol in the grammar for generation
+    /// * `seed` - A numeric seed to make generation deterministic
+    /// * `tokens` - Number of tokens to generate, a budget rather than a cutoff when bounded
+    /// * `termination` - How the walk decides it is done
+    pub fn generate_with_termination(
+        &self,
+        name: &str,
+        starting_node: String,
+        seed: u64,
+        tokens: usize,
+        termination: Termination,
+    ) -> Result<Vec<String>, ResrapError> {
         let prng = PRNG::new(seed);
+        self.graph(name)?
+            .walk_graph_with(prng, starting_node, tokens, termination)
+    }
+
+    /// Generates content with `tokens` as a soft target: instead of stopping mid-rule, the walk
+    /// keeps going until every open rule has finished.
+    ///
+    /// # Returns
+    /// The tokens along with how far they overshot `tokens`.
+    pub fn generate_complete_with_seed(
+        &self,
+        name: &str,
+        starting_node: String,
+        seed: u64,
+        tokens: usize,
+    ) -> Result<Completed, ResrapError> {
+        let prng = PRNG::new(seed);
+        self.graph(name)?
+            .walk_graph_complete(prng, starting_node, tokens)
+    }
+
+    /// Returns an iterator that generates tokens lazily from the grammar identified by 'name'.
+    /// Nothing is generated until the iterator is polled, and it yields the same tokens as
+    /// `generate_with_seed` for the same seed.
+    pub fn generator(
+        &self,
+        name: &str,
+        starting_node: String,
+        seed: u64,
+    ) -> Result<Generator<'_>, ResrapError> {
+        let prng = PRNG::new(seed);
+        self.graph(name)?.generator(prng, starting_node)
+    }
+
+    /// Starts a generation session on the grammar identified by 'name'.
+    /// The session hands out one long derivation in chunks with `next_chunk`, and can be
+    /// saved and restored to continue it later.
+    pub fn session(
+        &self,
+        name: &str,
+        starting_node: String,
+        seed: u64,
+    ) -> Result<GenerationSession<'_>, ResrapError> {
+        let prng = PRNG::new(seed);
+        self.graph(name)?.session(prng, starting_node)
+    }
+
+    /// Continues a session saved with `GenerationSession::save` on the same grammar.
+    /// Fails with `ResrapError::GrammarChanged` if the grammar under 'name' is not the one
+    /// the session was saved on.
+    pub fn restore_session(
+        &self,
+        name: &str,
+        state: SessionState,
+    ) -> Result<GenerationSession<'_>, ResrapError> {
+        GenerationSession::restore(self.graph(name)?, state)
+    }
+
+    /// Continues a session from bytes made by `SessionState::to_bytes`, possibly in another
+    /// process. Same as `restore_session` once the bytes are decoded.
+    pub fn restore_session_bytes(
+        &self,
+        name: &str,
+        bytes: &[u8],
+    ) -> Result<GenerationSession<'_>, ResrapError> {
+        self.restore_session(name, SessionState::from_bytes(bytes)?)
+    }
+
+    /// Generates content like `generate_with_termination`, returned as a derivation tree.
+    /// Rule nodes hold what each rule expanded to, leaves tell literals from regex output.
+    /// `Derivation::leaves` flattens the tree back into the plain token list.
+    pub fn generate_tree(
+        &self,
+        name: &str,
+        starting_node: String,
+        seed: u64,
+        tokens: usize,
+        termination: Termination,
+    ) -> Result<Derivation, ResrapError> {
+        let prng = PRNG::new(seed);
+        self.graph(name)?
+            .walk_graph_tree(prng, starting_node, tokens, termination)
+    }
+
+    /// Generates content like `generate_with_termination`, with every token tagged by the rule
+    /// that printed it, its byte offset in the joined output and whether it came from a
+    /// literal or a regex.
+    pub fn generate_annotated(
+        &self,
+        name: &str,
+        starting_node: String,
+        seed: u64,
+        tokens: usize,
+        termination: Termination,
+    ) -> Result<Vec<AnnotatedToken>, ResrapError> {
+        let prng = PRNG::new(seed);
+        self.graph(name)?
+            .walk_graph_annotated(prng, starting_node, tokens, termination)
+    }
+
+    /// Generates content like `generate_with_seed` on tokio's blocking pool, so long
+    /// generations don't stall the executor.
+    /// The returned future doesn't borrow `self` and can be spawned.
+    #[cfg(feature = "tokio")]
+    pub fn generate_async(
+        &self,
+        name: &str,
+        starting_node: String,
+        seed: u64,
+        tokens: usize,
+    ) -> impl Future<Output = Result<Vec<String>, ResrapError>> + Send + 'static {
+        let graph = self.shared_graph(name);
+        let prng = PRNG::new(seed);
+        async move { crate::core::stream::walk_graph_async(graph?, prng, starting_node, tokens).await }
+    }
+
+    /// Streams the tokens `generate_with_seed` would return while they are being generated,
+    /// for generations too large to hold at once. Has to be called inside a tokio runtime.
+    ///
+    /// # Returns
+    /// `ResrapError::UnknownGrammar` or `ResrapError::UnknownStartRule` right away, errors
+    /// during generation as an item of the stream.
+    #[cfg(feature = "tokio")]
+    pub fn generate_stream(
+        &self,
+        name: &str,
+        starting_node: String,
+        seed: u64,
+        tokens: usize,
+    ) -> Result<TokenStream, ResrapError> {
+        let prng = PRNG::new(seed);
+        crate::core::stream::token_stream(self.shared_graph(name)?, prng, starting_node, tokens)
+    }
+
+    /// Generates one sample per seed in parallel, each the same as `generate_with_seed`
+    /// would return for that seed. Results come back in the order of `seeds` no matter how
+    /// many threads rayon uses.
+    ///
+    /// # Returns
+    /// The samples, or the first error any of them ran into.
+    #[cfg(feature = "rayon")]
+    pub fn generate_batch(
+        &self,
+        name: &str,
+        starting_node: String,
+        seeds: impl IntoIterator<Item = u64>,
+        tokens: usize,
+    ) -> Result<Vec<Vec<String>>, ResrapError> {
+        let seeds: Vec<u64> = seeds.into_iter().collect();
+        self.graph(name)?
+            .walk_graph_batch(starting_node, &seeds, tokens)
+    }
+
+    /// Returns the warnings found while loading the grammar, such as rules that can never finish.
+    pub fn warnings(&self, name: &str) -> Result<Vec<Diagnostic>, ResrapError> {
+        Ok(self.lang(name)?.warnings())
+    }
+
+    /// Lints the probability annotations of a loaded grammar.
+    ///
+    /// # Returns
+    /// One warning per problem, naming the rule it was found in.
+    pub fn lint_grammar(&self, name: &str) -> Result<Vec<Diagnostic>, ResrapError> {
+        Ok(self.lang(name)?.lint())
+    }
+
+    /// Checks a loaded grammar for rules that can never finish and rules that
+    /// generation from `starting_node` can never reach.
+    ///
+    /// # Returns
+    /// One warning per problem, each pointing at the rule's definition.
+    pub fn check_grammar(
+        &self,
+        name: &str,
+        starting_node: &str,
+    ) -> Result<Vec<Diagnostic>, ResrapError> {
+        self.lang(name)?.check(starting_node)
+    }
+
+    fn lang(&self, name: &str) -> Result<&Lang, ResrapError> {
+        self.language_graph
+            .get(name)
+            .ok_or_else(|| ResrapError::UnknownGrammar(name.to_string()))
+    }
+
+    #[cfg(feature = "tokio")]
+    fn shared_graph(&self, name: &str) -> Result<std::sync::Arc<FrozenSyntaxGraph>, ResrapError> {
         self.language_graph
             .get(name)
-            .unwrap()
-            .get_graph()
-            .unwrap()
-            .walk_graph(prng, starting_node, tokens)
+            .and_then(Lang::shared_graph)
+            .ok_or_else(|| ResrapError::UnknownGrammar(name.to_string()))
+    }
+
+    fn graph(&self, name: &str) -> Result<&FrozenSyntaxGraph, ResrapError> {
+        self.language_graph
+            .get(name)
+            .and_then(Lang::get_graph)
+            .ok_or_else(|| ResrapError::UnknownGrammar(name.to_string()))
     }
 }
 
@@ -84,3 +393,186 @@ impl Default for Resrap {
         Self::new()
     }
 }
+
+/// ResrapMT is the access point for generating many snippets concurrently.
+/// Jobs go into a bounded queue that a pool of worker threads drains, and every result
+/// comes back on one channel tagged with the ID the job was submitted under.
+pub struct ResrapMT {
+    language_graph: HashMap<String, Lang>,
+    config: PoolConfig,
+    pool: Option<WorkerPool>,
+    // Dropped on shutdown, so the code channel ends once the workers are done with theirs
+    results_tx: Option<Sender<JobResult>>,
+    results_rx: Mutex<Option<Receiver<JobResult>>>,
+}
+
+impl ResrapMT {
+    /// Creates a new ResrapMT instance with no loaded grammars. No threads run until `start`.
+    ///
+    /// # Arguments
+    /// * `workers` - Number of worker threads, always running
+    /// * `queue_size` - Jobs that can wait in the queue before submitting blocks
+    pub fn new(workers: usize, queue_size: usize) -> Self {
+        Self::with_config(PoolConfig::fixed(workers, queue_size))
+    }
+
+    /// Creates a new ResrapMT instance whose pool grows and shrinks with the load.
+    /// Workers are added while jobs wait with every worker busy, up to `max_workers`, and
+    /// workers idle for longer than `idle_timeout` exit, down to `min_workers`.
+    pub fn with_config(config: PoolConfig) -> Self {
+        let (results_tx, results_rx) = mpsc::channel();
+        ResrapMT {
+            language_graph: HashMap::new(),
+            config,
+            pool: None,
+            results_tx: Some(results_tx),
+            results_rx: Mutex::new(Some(results_rx)),
+        }
+    }
+
+    /// Parses a grammar string and stores it under the given name, see `Resrap::parse_grammar`.
+    /// Jobs already queued keep the grammar they were submitted with.
+    pub fn parse_grammar(&mut self, name: String, grammar: String) -> Result<(), ResrapError> {
+        self.parse_grammar_with_config(name, grammar, LangConfig::default())
+    }
+
+    /// Parses a grammar string with grammar-wide settings,
+    /// see `Resrap::parse_grammar_with_config`.
+    pub fn parse_grammar_with_config(
+        &mut self,
+        name: String,
+        grammar: String,
+        config: LangConfig,
+    ) -> Result<(), ResrapError> {
+        let mut lang = Lang::with_config(config);
+        lang.parse_string(grammar)?;
+
+        self.language_graph.insert(name, lang);
+        Ok(())
+    }
+
+    /// Parses a grammar from a file and stores it under the given name,
+    /// see `Resrap::parse_grammar_file`.
+    pub fn parse_grammar_file(
+        &mut self,
+        name: String,
+        location: String,
+    ) -> Result<(), ResrapError> {
+        self.parse_grammar_file_with_config(name, location, LangConfig::default())
+    }
+
+    /// Parses a grammar from a file with grammar-wide settings,
+    /// see `Resrap::parse_grammar_file_with_config`.
+    pub fn parse_grammar_file_with_config(
+        &mut self,
+        name: String,
+        location: String,
+        config: LangConfig,
+    ) -> Result<(), ResrapError> {
+        let mut lang = Lang::with_config(config);
+        lang.parse_file(location)?;
+
+        self.language_graph.insert(name, lang);
+        Ok(())
+    }
+
+    /// Loads a compiled grammar and stores it under the given name,
+    /// see `Resrap::load_compiled_grammar`.
+    pub fn load_compiled_grammar(&mut self, name: String, bytes: &[u8]) -> Result<(), ResrapError> {
+        let mut lang = Lang::new();
+        lang.load_compiled(bytes)?;
+
+        self.language_graph.insert(name, lang);
+        Ok(())
+    }
+
+    /// Loads a compiled grammar from a file and stores it under the given name,
+    /// see `Resrap::load_compiled_grammar_file`.
+    pub fn load_compiled_grammar_file(
+        &mut self,
+        name: String,
+        location: String,
+    ) -> Result<(), ResrapError> {
+        let mut lang = Lang::new();
+        lang.load_compiled_file(location)?;
+
+        self.language_graph.insert(name, lang);
+        Ok(())
+    }
+
+    /// Spawns the worker threads. Does nothing if they are already running.
+    /// Starting again after `shutdown` opens a new code channel to take, results left on the
+    /// old one are dropped with it.
+    pub fn start(&mut self) {
+        if self.pool.is_some() {
+            return;
+        }
+        let results_tx = self.results_tx.get_or_insert_with(|| {
+            let (results_tx, results_rx) = mpsc::channel();
+            *self.results_rx.lock().unwrap() = Some(results_rx);
+            results_tx
+        });
+        self.pool = Some(WorkerPool::new(self.config.clone(), results_tx.clone()));
+    }
+
+    /// Hands out the channel every job result arrives on. There is only one, so this returns
+    /// None once it has been taken.
+    /// The channel is unbounded, results pile up in it until they are received.
+    pub fn take_code_channel(&self) -> Option<Receiver<JobResult>> {
+        self.results_rx.lock().unwrap().take()
+    }
+
+    /// Queues a generation job. Blocks while the queue is full.
+    ///
+    /// # Arguments
+    /// * `id` - Returned with the result, picking unique IDs is up to the caller
+    /// * `name` - The grammar name to use
+    /// * `starting_node` - The starting symbol in the grammar for generation
+    /// * `seed` - A numeric seed to make generation deterministic
+    /// * `tokens` - Number of tokens to generate
+    ///
+    /// # Returns
+    /// `ResrapError::UnknownGrammar` for a grammar that isn't loaded and
+    /// `ResrapError::PoolNotRunning` before `start` or after `shutdown`.
+    /// Errors from the walk itself arrive with the result.
+    pub fn generate_with_seed(
+        &self,
+        id: String,
+        name: &str,
+        starting_node: String,
+        seed: u64,
+        tokens: usize,
+    ) -> Result<(), ResrapError> {
+        let graph = self
+            .language_graph
+            .get(name)
+            .and_then(Lang::shared_graph)
+            .ok_or_else(|| ResrapError::UnknownGrammar(name.to_string()))?;
+        let pool = self.pool.as_ref().ok_or(ResrapError::PoolNotRunning)?;
+        pool.submit(Job {
+            id,
+            graph,
+            start: starting_node,
+            seed,
+            tokens,
+        })
+    }
+
+    /// Reports queue depth, live and busy workers and finished jobs.
+    /// All zero while the pool isn't running.
+    pub fn stats(&self) -> PoolStats {
+        self.pool
+            .as_ref()
+            .map_or_else(PoolStats::default, WorkerPool::stats)
+    }
+
+    /// Stops accepting jobs, waits for the queued ones to finish and stops the workers.
+    /// Their results are still delivered on the code channel, which then disconnects, so a
+    /// `for result in channel` loop ends. The pool can be started again.
+    pub fn shutdown(&mut self) {
+        if let Some(mut pool) = self.pool.take() {
+            pool.shutdown();
+        }
+        self.results_tx = None;
+    }
+}